@@ -1,11 +1,42 @@
+use std::process::Command;
+
 fn main() {
+    if let Ok(output) = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+    {
+        if output.status.success() {
+            let commit = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=WSL_GET_GIT_COMMIT={}", commit.trim());
+        }
+    }
+
     windows::build! {
+        Windows::Win32::Foundation::CloseHandle,
+        Windows::Win32::Security::SECURITY_ATTRIBUTES,
+        Windows::Win32::Storage::FileSystem::{
+            GetDiskFreeSpaceExW,
+            GetVolumeInformationW,
+            GetVolumePathNameW,
+            ReadFile,
+        },
         Windows::Win32::System::Com::CoTaskMemFree,
         Windows::Win32::System::LibraryLoader::{
             FreeLibrary,
             GetProcAddress,
             LoadLibraryExW,
         },
+        Windows::Win32::System::Console::GetStdHandle,
+        Windows::Win32::System::Pipes::CreatePipe,
+        Windows::Win32::System::Registry::{
+            RegCloseKey,
+            RegEnumKeyExW,
+            RegGetValueW,
+            RegOpenKeyExW,
+            HKEY_CURRENT_USER,
+        },
         Windows::Win32::System::SubsystemForLinux::*,
+        Windows::Win32::System::Threading::{GetExitCodeProcess, WaitForSingleObject},
+        Windows::Win32::System::WindowsProgramming::INFINITE,
     };
 }
@@ -0,0 +1,656 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Result};
+use flate2::{
+    read::{GzDecoder, MultiGzDecoder},
+    write::GzEncoder,
+    Compression,
+};
+
+use crate::output::status;
+
+/// Format of rootfs tarball
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "tar" => Self::Tar,
+            "gzip" | "gz" => Self::Gzip,
+            "zstd" | "zst" => Self::Zstd,
+            _ => bail!("Unknown format: {} (expected tar, gzip or zstd)", s),
+        })
+    }
+}
+
+impl ArchiveFormat {
+    /// Detect the format from the magic bytes of the file
+    pub fn detect(path: &Path) -> Result<Option<Self>> {
+        let mut header = vec![];
+        File::open(path)?.take(512).read_to_end(&mut header)?;
+
+        if header.starts_with(&[0x1f, 0x8b]) {
+            return Ok(Some(Self::Gzip));
+        }
+        if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            return Ok(Some(Self::Zstd));
+        }
+        if header.len() >= 262 && &header[257..262] == b"ustar" {
+            return Ok(Some(Self::Tar));
+        }
+        Ok(None)
+    }
+
+    /// Guess the format from the file name
+    pub fn from_file_name(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::Gzip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::Zstd)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Tar => ".tar",
+            Self::Gzip => ".tar.gz",
+            Self::Zstd => ".tar.zst",
+        }
+    }
+
+    /// Write the tarball read from `reader` to `writer` in this format.
+    /// `level` is the compression level (defaults to fast compression).
+    pub fn compress(
+        self,
+        reader: &mut impl Read,
+        mut writer: impl Write,
+        level: Option<i32>,
+    ) -> Result<()> {
+        match self {
+            Self::Tar => {
+                io::copy(reader, &mut writer)?;
+            }
+            Self::Gzip => {
+                let level = level.map_or(Compression::fast(), |level| {
+                    Compression::new(level.clamp(0, 9) as u32)
+                });
+                let mut encoder = GzEncoder::new(writer, level);
+                io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Self::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, level.unwrap_or(3))?;
+                io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format of the tarball handed to `wsl.exe --import`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Tar,
+    Gzip,
+}
+
+impl FromStr for ImportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" | "tar" => Self::Tar,
+            "gzip" | "gz" => Self::Gzip,
+            _ => bail!("Unknown import format: {} (expected none or gzip)", s),
+        })
+    }
+}
+
+impl ImportFormat {
+    /// All formats, the fastest to write and import first
+    pub const ALL: [Self; 2] = [Self::Tar, Self::Gzip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Tar => "none",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    pub fn archive_format(self) -> ArchiveFormat {
+        match self {
+            Self::Tar => ArchiveFormat::Tar,
+            Self::Gzip => ArchiveFormat::Gzip,
+        }
+    }
+
+    /// The fastest of `supported`, or gzip that every version of WSL accepts
+    pub fn fastest(supported: &[Self]) -> Self {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|format| supported.contains(format))
+            .unwrap_or(Self::Gzip)
+    }
+}
+
+/// Path of a tar entry relative to the root (e.g. `./usr/bin` -> `usr/bin`)
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+/// How `filter_rootfs` rewrites a rootfs
+#[derive(Debug, Default)]
+pub struct RootfsFilter {
+    /// Keep only the entries under any of these paths (all if empty).
+    /// Directories above them are kept to preserve their permissions.
+    pub include: Vec<PathBuf>,
+    /// Remove the entries under any of these paths
+    pub exclude: Vec<PathBuf>,
+    /// Sort entries by path and set their timestamps to this,
+    /// so that the same rootfs always results in the same bytes
+    pub mtime: Option<u64>,
+    /// Remove character and block devices. WSL mounts its own /dev,
+    /// and device nodes left by some images make `wsl.exe --import` warn or fail.
+    pub exclude_dev: bool,
+}
+
+impl RootfsFilter {
+    /// Whether the rootfs is copied as it is
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.mtime.is_none()
+            && !self.exclude_dev
+    }
+}
+
+/// Copy the tarball from `reader` to `writer` in `format`, rewritten by `filter`
+pub fn filter_rootfs(
+    mut reader: impl Read + Seek,
+    writer: impl Write,
+    format: ArchiveFormat,
+    level: Option<i32>,
+    filter: &RootfsFilter,
+) -> Result<()> {
+    let include = filter
+        .include
+        .iter()
+        .map(|p| normalize(p))
+        .collect::<Vec<_>>();
+    let exclude = filter
+        .exclude
+        .iter()
+        .map(|p| normalize(p))
+        .collect::<Vec<_>>();
+    let mtime = filter.mtime;
+
+    // First, find the entries to keep with their offsets,
+    // including the long name headers preceding them
+    let mut kept = vec![];
+    let mut offset = 0;
+    for entry in tar::Archive::new(&mut reader).entries()? {
+        let entry = entry?;
+        let start = offset;
+        offset = entry.raw_file_position() + entry.header().entry_size()?.div_ceil(512) * 512;
+
+        let path = normalize(&entry.path()?);
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let included = include.is_empty()
+            || include.iter().any(|p| path.starts_with(p))
+            || entry.header().entry_type().is_dir() && include.iter().any(|p| p.starts_with(&path));
+        if !included || exclude.iter().any(|p| path.starts_with(p)) {
+            continue;
+        }
+
+        let entry_type = entry.header().entry_type();
+        if filter.exclude_dev
+            && (entry_type.is_character_special() || entry_type.is_block_special())
+        {
+            continue;
+        }
+
+        let is_hard_link = entry.header().entry_type().is_hard_link();
+        kept.push((is_hard_link, path, start));
+    }
+
+    // Hard links go last, since their targets must be extracted before them
+    if mtime.is_some() {
+        kept.sort();
+    }
+
+    let rewrite = |writer: &mut dyn Write| -> Result<()> {
+        let mut builder = tar::Builder::new(writer);
+
+        for (_, path, start) in &kept {
+            reader.seek(SeekFrom::Start(*start))?;
+            let mut archive = tar::Archive::new(&mut reader);
+            let mut entry = match archive.entries()?.next() {
+                Some(entry) => entry?,
+                None => bail!("Failed to read entry {}", path.display()),
+            };
+
+            let mut header = entry.header().clone();
+            if let Some(mtime) = mtime {
+                header.set_mtime(mtime);
+                if let Some(gnu) = header.as_gnu_mut() {
+                    gnu.set_atime(mtime);
+                    gnu.set_ctime(mtime);
+                }
+            }
+
+            if let Some(link_name) = entry.link_name()? {
+                let link_name = link_name.into_owned();
+                builder.append_link(&mut header, path, link_name)?;
+            } else {
+                builder.append_data(&mut header, path, &mut entry)?;
+            }
+        }
+
+        builder.into_inner()?.flush()?;
+        Ok(())
+    };
+
+    write_compressed(writer, format, level, rewrite)
+}
+
+/// Give `f` a writer that compresses what is written into `writer` in `format`
+fn write_compressed(
+    writer: impl Write,
+    format: ArchiveFormat,
+    level: Option<i32>,
+    f: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    // The gzip header has no timestamp by default, so compression is reproducible as well
+    match format {
+        ArchiveFormat::Tar => f(&mut { writer })?,
+        ArchiveFormat::Gzip => {
+            let level = level.map_or(Compression::fast(), |level| {
+                Compression::new(level.clamp(0, 9) as u32)
+            });
+            let mut encoder = GzEncoder::new(writer, level);
+            f(&mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        ArchiveFormat::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, level.unwrap_or(3))?;
+            f(&mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Directories that everyone can write to, which need the sticky bit
+const STICKY_DIRS: &[&str] = &["tmp", "var/tmp"];
+
+/// Write the files under `dir` to `writer` as a rootfs tarball in `format`.
+/// Everything is owned by root, since Windows has no Unix ownership. Nor does it have
+/// the execute bit, so it is set on ELF binaries and scripts (`#!`).
+/// Returns the number of skipped entries that are neither files, directories nor symlinks.
+pub fn archive_dir(
+    dir: &Path,
+    writer: impl Write,
+    format: ArchiveFormat,
+    level: Option<i32>,
+) -> Result<usize> {
+    let mut skipped = 0;
+
+    write_compressed(writer, format, level, |writer| {
+        let mut builder = tar::Builder::new(writer);
+        append_dir(&mut builder, dir, Path::new(""), &mut skipped)?;
+        builder.into_inner()?.flush()?;
+        Ok(())
+    })?;
+
+    Ok(skipped)
+}
+
+/// Append the entries under `root/rel` in the order of their names, recursively
+fn append_dir(
+    builder: &mut tar::Builder<impl Write>,
+    root: &Path,
+    rel: &Path,
+    skipped: &mut usize,
+) -> Result<()> {
+    let mut entries = fs::read_dir(root.join(rel))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let rel = rel.join(entry.file_name());
+        let meta = fs::symlink_metadata(&path)?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&meta, tar::HeaderMode::Complete);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_username("root")?;
+        header.set_groupname("root")?;
+
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(&path)
+                .map_err(|err| anyhow!("Failed to read symlink {}: {}", path.display(), err))?;
+            builder.append_link(&mut header, &rel, target)?;
+        } else if meta.is_dir() {
+            if STICKY_DIRS.iter().any(|dir| rel == Path::new(dir)) {
+                header.set_mode(0o1777);
+            }
+            builder.append_data(&mut header, &rel, io::empty())?;
+            append_dir(builder, root, &rel, skipped)?;
+        } else if meta.is_file() {
+            let mut file = File::open(&path)?;
+            let mut magic = vec![];
+            (&mut file).take(4).read_to_end(&mut magic)?;
+            file.seek(SeekFrom::Start(0))?;
+            if magic.starts_with(b"\x7fELF") || magic.starts_with(b"#!") {
+                header.set_mode(header.mode()? | 0o111);
+            }
+            builder.append_data(&mut header, &rel, file)?;
+        } else {
+            // Sockets, FIFOs and devices, which WSL creates on its own
+            *skipped += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Estimate the size of the tarball after decompression
+pub fn uncompressed_size(path: &Path) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    match ArchiveFormat::detect(path)? {
+        // The gzip footer has the size modulo 2^32 (ISIZE), which can be trusted only if
+        // the data can't reach 4GiB even at the maximum ratio of deflate
+        Some(ArchiveFormat::Gzip) if size >= 4 && size * MAX_DEFLATE_RATIO <= u32::MAX as u64 => {
+            let mut isize = [0; 4];
+            file.seek(SeekFrom::End(-4))?;
+            file.read_exact(&mut isize)?;
+            Ok(u32::from_le_bytes(isize) as u64)
+        }
+        Some(ArchiveFormat::Gzip) => Ok(io::copy(
+            &mut MultiGzDecoder::new(BufReader::new(file)),
+            &mut io::sink(),
+        )?),
+        Some(ArchiveFormat::Zstd) => match zstd_content_size(&mut BufReader::new(&mut file))? {
+            Some(size) => Ok(size),
+            // Streamed frames may not have the size, so decompress to count
+            None => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(io::copy(&mut zstd::Decoder::new(file)?, &mut io::sink())?)
+            }
+        },
+        _ => Ok(size),
+    }
+}
+
+/// Deflate can't compress better than about 1032:1
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+/// Sum of the content sizes in the headers of zstd frames, or `None` if any frame lacks it.
+/// Blocks are skipped by their headers without decompressing them (RFC 8878).
+fn zstd_content_size<R: Read + Seek>(reader: &mut R) -> Result<Option<u64>> {
+    const MAGIC: u32 = 0xFD2FB528;
+
+    let mut total = 0;
+
+    loop {
+        let mut magic = [0; 4];
+        match reader.read_exact(&mut magic) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(Some(total)),
+            Err(err) => return Err(err.into()),
+        }
+        let magic = u32::from_le_bytes(magic);
+
+        // Skippable frames (e.g. metadata) have their length after the magic number
+        if magic & 0xFFFFFFF0 == 0x184D2A50 {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            reader.seek(SeekFrom::Current(u32::from_le_bytes(len) as i64))?;
+            continue;
+        }
+        if magic != MAGIC {
+            bail!("Invalid zstd frame");
+        }
+
+        let mut descriptor = [0; 1];
+        reader.read_exact(&mut descriptor)?;
+        let descriptor = descriptor[0];
+
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let dict_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        let content_size_size = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => return Ok(None),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+
+        // Window descriptor and dictionary ID
+        let skip = if single_segment { 0 } else { 1 } + dict_id_size;
+        reader.seek(SeekFrom::Current(skip))?;
+
+        let mut content_size = [0; 8];
+        reader.read_exact(&mut content_size[..content_size_size])?;
+        total += match content_size_size {
+            // Sizes of 256..=65791 are stored in 2 bytes offset by 256
+            2 => u64::from_le_bytes(content_size) + 256,
+            _ => u64::from_le_bytes(content_size),
+        };
+
+        loop {
+            let mut header = [0; 4];
+            reader.read_exact(&mut header[..3])?;
+            let header = u32::from_le_bytes(header);
+
+            let last = header & 1 != 0;
+            let block_size = match (header >> 1) & 0x03 {
+                // RLE blocks have a single byte
+                1 => 1,
+                3 => bail!("Invalid zstd block"),
+                _ => header >> 3,
+            };
+            reader.seek(SeekFrom::Current(block_size as i64))?;
+
+            if last {
+                break;
+            }
+        }
+
+        if has_checksum {
+            reader.seek(SeekFrom::Current(4))?;
+        }
+    }
+}
+
+/// Machine types in the ELF header, named as `std::env::consts::ARCH`
+const ELF_MACHINES: &[(u16, &str)] = &[
+    (0x03, "x86"),
+    (0x28, "arm"),
+    (0x3e, "x86_64"),
+    (0xb7, "aarch64"),
+    (0xf3, "riscv64"),
+];
+
+/// Guess the architecture of a rootfs tarball from the first ELF executable in it
+pub fn rootfs_arch(path: &Path) -> Result<Option<&'static str>> {
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match ArchiveFormat::detect(path)? {
+        Some(ArchiveFormat::Gzip) => Box::new(GzDecoder::new(file)),
+        Some(ArchiveFormat::Zstd) => Box::new(zstd::Decoder::new(file)?),
+        Some(ArchiveFormat::Tar) => Box::new(file),
+        None => return Ok(None),
+    };
+
+    for entry in tar::Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() || entry.header().mode()? & 0o111 == 0 {
+            continue;
+        }
+
+        let mut header = [0; 20];
+        if entry.read_exact(&mut header).is_err() || !header.starts_with(b"\x7fELF") {
+            continue;
+        }
+
+        // e_machine follows the byte order given by EI_DATA (1: little, 2: big endian)
+        let machine = match header[5] {
+            2 => u16::from_be_bytes([header[18], header[19]]),
+            _ => u16::from_le_bytes([header[18], header[19]]),
+        };
+        return Ok(ELF_MACHINES
+            .iter()
+            .find(|(m, _)| *m == machine)
+            .map(|(_, arch)| *arch));
+    }
+
+    Ok(None)
+}
+
+/// Convert `path` to a temporary file in `temp_dir` that `wsl.exe --import` accepts, if needed.
+/// WSL accepts only tar and gzip, and decides the format by the file extension.
+pub fn prepare_for_import(path: &Path, temp_dir: &Path) -> Result<Option<tempfile::TempPath>> {
+    let detected = match ArchiveFormat::detect(path)? {
+        Some(format) => format,
+        None => {
+            status!(
+                "Warning: could not detect the format of `{}`",
+                path.display()
+            );
+            return Ok(None);
+        }
+    };
+
+    if detected == ArchiveFormat::Zstd {
+        status!("Decompressing {}...", path.display());
+
+        let mut temp_file = tempfile::Builder::new()
+            .suffix(ArchiveFormat::Tar.extension())
+            .tempfile_in(temp_dir)?;
+        zstd::stream::copy_decode(File::open(path)?, &mut temp_file)?;
+
+        return Ok(Some(temp_file.into_temp_path()));
+    }
+
+    if ArchiveFormat::from_file_name(path) == Some(detected) {
+        return Ok(None);
+    }
+
+    status!(
+        "Warning: `{}` is actually a {} file",
+        path.display(),
+        detected.extension()
+    );
+
+    let mut temp_file = tempfile::Builder::new()
+        .suffix(detected.extension())
+        .tempfile_in(temp_dir)?;
+    io::copy(&mut File::open(path)?, &mut temp_file)?;
+
+    Ok(Some(temp_file.into_temp_path()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compressible data that is not a single repeated byte
+    fn data(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i % 251) as u8 ^ (i / 4096) as u8)
+            .collect()
+    }
+
+    fn write_temp(name: &str, content: &[u8]) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::write(&path, content).unwrap();
+        (dir, path)
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzip_size() {
+        let (_dir, path) = write_temp("rootfs.tar.gz", &gzip(&data(300_000)));
+        assert_eq!(uncompressed_size(&path).unwrap(), 300_000);
+
+        // Large enough for ISIZE to have wrapped, so counted by decompressing.
+        // ISIZE is of the last member only as well.
+        let mut seed = 1u32;
+        let random = (0..5_000_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect::<Vec<_>>();
+        let mut members = gzip(&random);
+        members.extend(gzip(&data(50_000)));
+        let (_dir, path) = write_temp("rootfs.tar.gz", &members);
+        assert_eq!(uncompressed_size(&path).unwrap(), 5_050_000);
+    }
+
+    #[test]
+    fn zstd_size_from_frame_headers() {
+        let mut frames = zstd::bulk::compress(&data(1_000_000), 3).unwrap();
+        frames.extend(zstd::bulk::compress(&data(100), 3).unwrap());
+        frames.extend(zstd::bulk::compress(&data(300), 3).unwrap());
+        frames.extend(zstd::bulk::compress(&[], 3).unwrap());
+        // Skippable frame
+        frames.extend([0x50, 0x2A, 0x4D, 0x18, 3, 0, 0, 0, 1, 2, 3]);
+
+        assert_eq!(
+            zstd_content_size(&mut io::Cursor::new(&frames)).unwrap(),
+            Some(1_000_400)
+        );
+
+        let (_dir, path) = write_temp("rootfs.tar.zst", &frames);
+        assert_eq!(uncompressed_size(&path).unwrap(), 1_000_400);
+    }
+
+    #[test]
+    fn zstd_size_of_streamed_frames() {
+        // The streaming encoder doesn't know the size in advance
+        let compressed = zstd::encode_all(&data(200_000)[..], 3).unwrap();
+        assert_eq!(
+            zstd_content_size(&mut io::Cursor::new(&compressed)).unwrap(),
+            None
+        );
+
+        let (_dir, path) = write_temp("rootfs.tar.zst", &compressed);
+        assert_eq!(uncompressed_size(&path).unwrap(), 200_000);
+    }
+}
@@ -1 +1,3 @@
+#![allow(unused_must_use, clippy::all)]
+
 windows::include_bindings!();
@@ -0,0 +1,85 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use anyhow::Result;
+
+type Action = Box<dyn FnOnce() + Send>;
+
+/// Cleanup actions not run yet, in the order of registration
+static PENDING: Mutex<Vec<(usize, Action)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn pending() -> MutexGuard<'static, Vec<(usize, Action)>> {
+    PENDING.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Handle of a registered cleanup action.
+/// Whichever of dropping it or Ctrl-C comes first takes the action, so it never runs twice.
+#[must_use]
+pub struct Guard {
+    id: usize,
+    run_on_drop: bool,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let action = {
+            let mut pending = pending();
+            let index = pending.iter().position(|(id, _)| *id == self.id);
+            index.map(|index| pending.remove(index).1)
+        };
+
+        if let Some(action) = action.filter(|_| self.run_on_drop) {
+            action();
+        }
+    }
+}
+
+fn register(action: Action, run_on_drop: bool) -> Guard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    pending().push((id, action));
+    Guard { id, run_on_drop }
+}
+
+/// Run `action` when the guard is dropped or the process is interrupted
+pub fn on_exit(action: impl FnOnce() + Send + 'static) -> Guard {
+    register(Box::new(action), true)
+}
+
+/// Run `action` only if the process is interrupted while the guard is alive.
+/// For resources that clean up themselves on drop, such as `tempfile`'s.
+pub fn on_interrupt(action: impl FnOnce() + Send + 'static) -> Guard {
+    register(Box::new(action), false)
+}
+
+/// Remove a temporary file if the process is interrupted
+pub fn remove_on_interrupt(path: &Path) -> Guard {
+    let path = path.to_owned();
+    on_interrupt(move || {
+        let _ = fs::remove_file(path);
+    })
+}
+
+/// Run pending cleanup on Ctrl-C, newest first, and exit
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        let actions = std::mem::take(&mut *pending());
+
+        if !actions.is_empty() {
+            eprintln!("Interrupted. Cleaning up...");
+        }
+        for (_, action) in actions.into_iter().rev() {
+            action();
+        }
+
+        std::process::exit(130);
+    })?;
+
+    Ok(())
+}
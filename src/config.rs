@@ -0,0 +1,164 @@
+use std::{collections::BTreeMap, fs, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use directories::BaseDirs;
+use serde::Deserialize;
+
+use crate::{container::Runtime, registry::Repository};
+
+/// Directory for user settings and logs of wsl-get
+pub fn config_dir() -> Result<PathBuf> {
+    let base_dirs = BaseDirs::new().ok_or_else(|| anyhow!("Failed to get home directory"))?;
+    Ok(base_dirs.config_dir().join("wsl-get"))
+}
+
+/// Settings in `wsl-get.toml`
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+    /// Tag used when the image has none, by registry or repository (`[default-tags]`)
+    #[serde(default, rename = "default-tags")]
+    pub default_tags: BTreeMap<String, String>,
+    /// Free space to keep on the volume of the rootfs cache (`min-free-space`)
+    #[serde(rename = "min-free-space")]
+    pub min_free_space: Option<ByteSize>,
+}
+
+/// Number of bytes with an optional binary unit (e.g. `512M`, `10G` or `10GiB`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+
+        let shift = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 0,
+            "K" | "KB" | "KIB" => 10,
+            "M" | "MB" | "MIB" => 20,
+            "G" | "GB" | "GIB" => 30,
+            "T" | "TB" | "TIB" => 40,
+            _ => bail!("Invalid size: {} (e.g. 512M or 10G)", s),
+        };
+        let number: u64 = number
+            .parse()
+            .map_err(|_| anyhow!("Invalid size: {} (e.g. 512M or 10G)", s))?;
+
+        number
+            .checked_mul(1 << shift)
+            .map(Self)
+            .ok_or_else(|| anyhow!("Size is too large: {}", s))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Named set of install options (`[profile.<name>]`)
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default, deserialize_with = "deserialize_runtime")]
+    pub runtime: Option<Runtime>,
+    pub registry_mirror: Option<String>,
+    #[serde(default)]
+    pub mirror_fallback: bool,
+    #[serde(default)]
+    pub prefer_docker_hub: bool,
+    pub data_dir_name: Option<String>,
+    pub prefix: Option<String>,
+    pub user_home: Option<String>,
+    #[serde(default)]
+    pub no_password: bool,
+    #[serde(default)]
+    pub nopasswd_sudo: bool,
+    #[serde(default)]
+    pub no_group_add: bool,
+    #[serde(default)]
+    pub no_systemd: bool,
+    #[serde(default)]
+    pub fix_network: bool,
+    pub hostname: Option<String>,
+    pub default_command: Option<String>,
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    #[serde(default)]
+    pub useradd_args: Vec<String>,
+}
+
+fn deserialize_runtime<'de, D>(deserializer: D) -> Result<Option<Runtime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let runtime = Option::<String>::deserialize(deserializer)?;
+    runtime
+        .map(|runtime| runtime.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        Ok(config_dir()?.join("wsl-get.toml"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        toml::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Default tag of the image from the most specific entry of `[default-tags]`.
+    /// Keys are a registry (`ghcr.io`), or a repository or its prefix (`ghcr.io/org`).
+    pub fn default_tag(&self, image: &str) -> Option<&str> {
+        let repo = Repository::parse(image);
+
+        self.default_tags
+            .iter()
+            .filter_map(|(key, tag)| {
+                let is_registry = !key.contains('/')
+                    && (key.contains('.') || key.contains(':') || key == "localhost");
+                if is_registry {
+                    let registry = Repository::parse(&format!("{}/_", key)).registry;
+                    return (registry == repo.registry).then_some((0, tag));
+                }
+
+                let prefix = Repository::parse(key);
+                let matched = prefix.registry == repo.registry
+                    && (repo.name == prefix.name
+                        || repo.name.starts_with(&format!("{}/", prefix.name)));
+                matched.then_some((prefix.name.len() + 1, tag))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, tag)| tag.as_str())
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profile.get(name).ok_or_else(|| {
+            anyhow!(
+                "Profile `{}` is not defined in {}",
+                name,
+                Self::path()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default()
+            )
+        })
+    }
+}
@@ -0,0 +1,816 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, ErrorKind, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use indicatif::ProgressBar;
+
+use crate::{
+    archive::{self, ArchiveFormat},
+    cleanup,
+    error::Failure,
+    metadata::cache_root,
+    output::{self, status},
+    progress::{self, InstallEvent},
+    registry::{self, Repository},
+    wsl::free_space,
+};
+
+/// Container runtime whose CLI is compatible with docker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    Docker,
+    Podman,
+    Nerdctl,
+}
+
+impl Runtime {
+    fn command(self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+            Self::Nerdctl => "nerdctl",
+        }
+    }
+
+    /// Explain how to get along without the runtime if it is not installed
+    fn spawn_error(self, err: io::Error) -> anyhow::Error {
+        if err.kind() != ErrorKind::NotFound {
+            return err.into();
+        }
+        let message = match self {
+            Self::Docker => {
+                "docker not found on PATH; install Docker Desktop or use --runtime podman / --from-file"
+            }
+            Self::Podman => {
+                "podman not found on PATH; install Podman or use --runtime docker / --from-file"
+            }
+            Self::Nerdctl => {
+                "nerdctl not found on PATH; install nerdctl or use --runtime docker / --from-file"
+            }
+        };
+        Failure::RuntimeMissing(message.to_string()).into()
+    }
+}
+
+impl FromStr for Runtime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "docker" => Self::Docker,
+            "podman" => Self::Podman,
+            "nerdctl" => Self::Nerdctl,
+            _ => bail!(
+                "Unknown runtime: {} (expected docker, podman or nerdctl)",
+                s
+            ),
+        })
+    }
+}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.command())
+    }
+}
+
+/// When to pull images instead of using the cached rootfs, like docker's `--pull`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PullPolicy {
+    Always,
+    #[default]
+    Missing,
+    Never,
+}
+
+impl FromStr for PullPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "always" => Self::Always,
+            "missing" => Self::Missing,
+            "never" => Self::Never,
+            _ => bail!(
+                "Unknown pull policy: {} (expected always, missing or never)",
+                s
+            ),
+        })
+    }
+}
+
+/// Options for pulling container images
+#[derive(Debug, Default)]
+pub struct PullOptions {
+    pub verbose: bool,
+    /// Runtimes to try in order
+    pub runtimes: Vec<Runtime>,
+    pub registry_mirror: Option<String>,
+    /// Pull from the original registry if the mirror fails
+    pub mirror_fallback: bool,
+    /// Pull from Docker Hub first, and from the mirror only if it fails
+    pub prefer_docker_hub: bool,
+    pub pull_policy: PullPolicy,
+    /// Free space to keep on the volume of the rootfs cache, removing old entries if needed
+    pub min_free_space: Option<u64>,
+}
+
+impl PullOptions {
+    pub fn new(
+        verbose: bool,
+        runtime: Option<Runtime>,
+        registry_mirror: Option<&str>,
+        mirror_fallback: bool,
+        prefer_docker_hub: bool,
+        pull_policy: PullPolicy,
+        min_free_space: Option<u64>,
+    ) -> Result<Self> {
+        Ok(Self {
+            verbose,
+            runtimes: match runtime {
+                Some(runtime) => vec![runtime],
+                None => vec![Runtime::Docker, Runtime::Podman, Runtime::Nerdctl],
+            },
+            registry_mirror: registry_mirror.map(registry::parse_mirror).transpose()?,
+            mirror_fallback,
+            prefer_docker_hub,
+            pull_policy,
+            min_free_space,
+        })
+    }
+
+    /// References of the image to pull, in the order to try
+    pub fn images(&self, distro: &str, tag: &str) -> Vec<String> {
+        let repo = Repository::parse(distro);
+
+        let mut images = vec![];
+
+        // Like docker's `registry-mirrors`, mirrors are used only for Docker Hub images
+        match &self.registry_mirror {
+            Some(mirror) if repo.is_docker_hub() => {
+                let original = format!("{}:{}", distro, tag);
+                let mirrored = format!("{}/{}:{}", mirror, repo.name, tag);
+                if self.prefer_docker_hub {
+                    images.push(original);
+                    images.push(mirrored);
+                } else {
+                    images.push(mirrored);
+                    if self.mirror_fallback {
+                        images.push(original);
+                    }
+                }
+            }
+            _ => images.push(format!("{}:{}", distro, tag)),
+        }
+
+        images
+    }
+
+    /// List of ways to pull the image, in the order to try
+    fn strategies(&self, distro: &str, tag: &str) -> Vec<PullStrategy> {
+        let images = self.images(distro, tag);
+
+        self.runtimes
+            .iter()
+            .flat_map(|&runtime| {
+                images.iter().map(move |image| PullStrategy {
+                    runtime,
+                    image: image.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct PullStrategy {
+    runtime: Runtime,
+    image: String,
+}
+
+/// Pull the image and save its rootfs to `path`.
+/// Returns the digest of the image if available.
+pub fn get_distribution_rootfs(
+    distro: &str,
+    tag: &str,
+    path: &Path,
+    format: ArchiveFormat,
+    level: Option<i32>,
+    opts: &PullOptions,
+) -> Result<Option<String>> {
+    let cache = RootfsCache::new(distro, tag, format)?;
+
+    if opts.pull_policy != PullPolicy::Always && cache.path.is_file() {
+        progress::emit(InstallEvent::CachedRootfs {
+            image: &format!("{}:{}", distro, tag),
+        });
+        fs::copy(&cache.path, path)?;
+        return Ok(cache.digest());
+    }
+
+    if opts.pull_policy == PullPolicy::Never {
+        bail!(Failure::NotFound(format!(
+            "Rootfs of {}:{} is not cached. Use --pull-policy missing to pull it",
+            distro, tag
+        )));
+    }
+
+    // Make room before pulling, since the pull and the export take space as well
+    let cacheable = match opts.min_free_space {
+        Some(min_free_space) => cache.make_room(min_free_space)?,
+        None => true,
+    };
+
+    let PullStrategy { runtime, image } = pull(distro, tag, opts)?;
+
+    export_rootfs(runtime, &image, path, format, level, opts.verbose)?;
+
+    // Some versions of docker export an image for another architecture without complaint
+    if let Some(arch) = archive::rootfs_arch(path)? {
+        // 32-bit x86 binaries run on x86_64 as well
+        let host = std::env::consts::ARCH;
+        if arch != host && !(arch == "x86" && host == "x86_64") {
+            let _ = fs::remove_file(path);
+            bail!(
+                "Pulled {} is built for {}, which does not run on this machine ({})",
+                image,
+                arch,
+                host
+            );
+        }
+    }
+
+    let digest = image_digest(runtime, &image);
+
+    if !cacheable {
+        progress::emit(InstallEvent::Warning {
+            message: "not caching rootfs since the cache volume is short of free space",
+        });
+    } else if let Err(err) = cache.store(path, digest.as_deref()) {
+        progress::emit(InstallEvent::Warning {
+            message: &format!("failed to cache rootfs: {:#}", err),
+        });
+    }
+
+    Ok(digest)
+}
+
+/// Rootfs being exported from a temporary container as an uncompressed tarball.
+/// The export is killed and the container is removed when this is dropped.
+pub struct ExportStream {
+    child: Child,
+    runtime: Runtime,
+    image: String,
+    _container_guard: cleanup::Guard,
+}
+
+impl ExportStream {
+    /// Pull the image and start exporting its rootfs, bypassing the rootfs cache
+    pub fn start(distro: &str, tag: &str, opts: &PullOptions) -> Result<Self> {
+        let PullStrategy { runtime, image } = pull(distro, tag, opts)?;
+
+        progress::emit(InstallEvent::Exporting { source: None });
+
+        let (id, container_guard) = create_container(runtime, &image, opts.verbose)?;
+        let child = Command::new(runtime.command())
+            .arg("export")
+            .arg(&id)
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| runtime.spawn_error(err))?;
+
+        Ok(Self {
+            child,
+            runtime,
+            image,
+            _container_guard: container_guard,
+        })
+    }
+
+    /// The tarball, to be read by another process
+    pub fn stdout(&mut self) -> Stdio {
+        self.child.stdout.take().unwrap().into()
+    }
+
+    /// Wait for the export to finish and return the digest of the image if available
+    pub fn finish(mut self) -> Result<Option<String>> {
+        if !self.child.wait()?.success() {
+            bail!("Failed to export {}", self.image);
+        }
+        Ok(image_digest(self.runtime, &self.image))
+    }
+}
+
+impl Drop for ExportStream {
+    fn drop(&mut self) {
+        // Does nothing if the export has finished
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Pull the image with the first strategy that works
+fn pull(distro: &str, tag: &str, opts: &PullOptions) -> Result<PullStrategy> {
+    progress::emit(InstallEvent::Pulling);
+
+    let mut pulled = None;
+    let mut runtime_missing = true;
+    for strategy in opts.strategies(distro, tag) {
+        match pull_image(strategy.runtime, &strategy.image, opts.verbose) {
+            Ok(()) => {
+                pulled = Some(strategy);
+                break;
+            }
+            Err(err) => {
+                runtime_missing &= err.downcast_ref::<Failure>().is_some();
+                eprintln!("{:#}", err)
+            }
+        }
+    }
+
+    let strategy = match pulled {
+        Some(strategy) => strategy,
+        None if runtime_missing => bail!(Failure::RuntimeMissing(
+            "No container runtime found on PATH; install Docker Desktop or use --from-file"
+                .to_string()
+        )),
+        None => bail!("Failed to pull distribution: {}:{}", distro, tag),
+    };
+
+    progress::emit(InstallEvent::Pulled {
+        image: &strategy.image,
+        runtime: &strategy.runtime.to_string(),
+    });
+
+    Ok(strategy)
+}
+
+/// Rootfs tarballs exported before, with the digest of the image
+struct RootfsCache {
+    path: PathBuf,
+    digest_path: PathBuf,
+}
+
+impl RootfsCache {
+    fn new(distro: &str, tag: &str, format: ArchiveFormat) -> Result<Self> {
+        let name = format!("{}-{}", distro, tag).replace(
+            |c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '_' && c != '-',
+            "-",
+        );
+        let dir = cache_root()?.join("rootfs");
+        Ok(Self {
+            path: dir.join(format!("{}{}", name, format.extension())),
+            digest_path: dir.join(format!("{}.digest", name)),
+        })
+    }
+
+    fn digest(&self) -> Option<String> {
+        let digest = fs::read_to_string(&self.digest_path).ok()?;
+        Some(digest.trim().to_string()).filter(|digest| !digest.is_empty())
+    }
+
+    /// Remove cached rootfs tarballs, oldest first, until `min_free_space` is free on the volume.
+    /// Returns false if it is still short, in which case nothing should be cached.
+    fn make_room(&self, min_free_space: u64) -> Result<bool> {
+        let dir = self.path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+
+        if free_space(dir)? >= min_free_space {
+            return Ok(true);
+        }
+
+        let mut entries = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext != "digest"))
+            .filter_map(|path| {
+                let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok()?;
+                Some((modified, path))
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        for (_, path) in entries {
+            status!("Removing cached {} to free space...", path.display());
+            fs::remove_file(&path)?;
+            let _ = fs::remove_file(dir.join(format!("{}.digest", cache_entry_name(&path))));
+
+            if free_space(dir)? >= min_free_space {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn store(&self, rootfs: &Path, digest: Option<&str>) -> Result<()> {
+        fs::create_dir_all(self.path.parent().unwrap())?;
+        fs::copy(rootfs, &self.path)?;
+        fs::write(&self.digest_path, digest.unwrap_or_default())?;
+        Ok(())
+    }
+}
+
+/// Name of a cached rootfs tarball without the extension of its format
+fn cache_entry_name(path: &Path) -> String {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    [ArchiveFormat::Gzip, ArchiveFormat::Zstd, ArchiveFormat::Tar]
+        .iter()
+        .find_map(|format| file_name.strip_suffix(format.extension()))
+        .unwrap_or(&file_name)
+        .to_string()
+}
+
+/// Save the rootfs of a local image to `path` through a temporary container
+pub fn export_rootfs(
+    runtime: Runtime,
+    image: &str,
+    path: &Path,
+    format: ArchiveFormat,
+    level: Option<i32>,
+    verbose: bool,
+) -> Result<()> {
+    progress::emit(InstallEvent::Exporting { source: None });
+
+    let (id, _container_guard) = create_container(runtime, image, verbose)?;
+
+    // Create it next to `path`, since persisting can't move it across volumes
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    let _temp_guard = cleanup::remove_on_interrupt(temp_file.path());
+
+    // All runtimes write the filesystem as an uncompressed tar to stdout
+    let mut child = Command::new(runtime.command())
+        .arg("export")
+        .arg(&id)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| runtime.spawn_error(err))?;
+
+    let stdout = child.stdout.as_mut().unwrap();
+
+    format.compress(stdout, File::create(&mut temp_file)?, level)?;
+
+    if !child.wait()?.success() {
+        bail!("Failed to save distribution tarball");
+    }
+
+    let file = temp_file.persist(path)?;
+
+    progress::emit(InstallEvent::Exported {
+        bytes: file.metadata()?.len(),
+    });
+
+    Ok(())
+}
+
+/// Create a container of `image` to export, which is removed when the guard is dropped
+fn create_container(
+    runtime: Runtime,
+    image: &str,
+    verbose: bool,
+) -> Result<(String, cleanup::Guard)> {
+    let output = Command::new(runtime.command())
+        .arg("create")
+        .arg(image)
+        .output()
+        .map_err(|err| runtime.spawn_error(err))?;
+
+    if !output.status.success() {
+        bail!("Failed to create container");
+    }
+
+    // Some versions print warnings (e.g. about the platform) to stdout before the id
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let id = stdout
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .unwrap_or_default()
+        .to_owned();
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!(
+            "Failed to get the container id from the output of `{} create`:\n{}",
+            runtime,
+            stdout.trim_end()
+        );
+    }
+
+    let guard = {
+        let id = id.clone();
+        cleanup::on_exit(move || remove_container(runtime, &id, verbose))
+    };
+
+    Ok((id, guard))
+}
+
+/// Build an image from `dockerfile`, using its directory as the build context
+pub fn build_image(
+    runtime: Runtime,
+    dockerfile: &Path,
+    build_args: &[String],
+    tag: &str,
+    verbose: bool,
+) -> Result<()> {
+    let context = dockerfile
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    let mut cmd = Command::new(runtime.command());
+    cmd.arg("build")
+        .arg("-t")
+        .arg(tag)
+        .arg("-f")
+        .arg(dockerfile);
+    for build_arg in build_args {
+        cmd.arg("--build-arg").arg(build_arg);
+    }
+    cmd.arg(context);
+
+    let output = if verbose {
+        cmd.stdout(output::child_stdout())
+            .status()
+            .map(|status| (status, vec![]))
+    } else {
+        let spinner = ProgressBar::new_spinner();
+        spinner.set_message(format!("Building {}", dockerfile.display()));
+        spinner.enable_steady_tick(100);
+        let output = cmd.output();
+        spinner.finish_and_clear();
+        output.map(|output| (output.status, output.stderr))
+    };
+
+    let (status, stderr) = output.map_err(|err| runtime.spawn_error(err))?;
+
+    if !status.success() {
+        bail!(
+            "Failed to build {} with {}\n{}",
+            dockerfile.display(),
+            runtime,
+            String::from_utf8_lossy(&stderr).trim_end()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn remove_image(runtime: Runtime, image: &str) {
+    let output = Command::new(runtime.command())
+        .args(["rmi", image])
+        .output();
+
+    if !output.is_ok_and(|output| output.status.success()) {
+        eprintln!("Failed to remove image {}", image);
+    }
+}
+
+fn remove_container(runtime: Runtime, id: &str, verbose: bool) {
+    let output = match Command::new(runtime.command()).arg("rm").arg(id).output() {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("Failed to remove container: {}", err);
+            return;
+        }
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if verbose && !stderr.trim().is_empty() {
+        eprintln!("{}", stderr.trim_end());
+    }
+
+    // The container may be already removed by docker itself
+    if !output.status.success() && !stderr.to_lowercase().contains("no such container") {
+        eprintln!("Failed to remove container");
+    }
+}
+
+/// Get the content-addressable reference (e.g. `ubuntu@sha256:...`) of the pulled image
+fn image_digest(runtime: Runtime, image: &str) -> Option<String> {
+    let output = Command::new(runtime.command())
+        .args(["image", "inspect", "--format", "{{index .RepoDigests 0}}"])
+        .arg(image)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+fn pull_image(runtime: Runtime, image: &str, verbose: bool) -> Result<()> {
+    let mut logged_in = false;
+
+    loop {
+        let output = run_pull(runtime, image, verbose)?;
+        if output.status.success() {
+            return Ok(());
+        }
+
+        // Credentials in docker's config.json are used by the runtime itself,
+        // so ask for them only if they are missing or rejected.
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !logged_in && is_auth_error(&stderr) && io::stdin().is_terminal() {
+            login(runtime, &Repository::parse(image))?;
+            logged_in = true;
+            continue;
+        }
+
+        bail!(
+            "Failed to pull {} with {}\n{}",
+            image,
+            runtime,
+            stderr.trim_end()
+        );
+    }
+}
+
+fn run_pull(runtime: Runtime, image: &str, verbose: bool) -> Result<Output> {
+    if verbose {
+        return Command::new(runtime.command())
+            .arg("pull")
+            .arg(image)
+            .stdout(output::child_stdout())
+            .output()
+            .map_err(|err| runtime.spawn_error(err));
+    }
+
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_message(format!("Pulling {}", image));
+    spinner.enable_steady_tick(100);
+
+    let output = Command::new(runtime.command())
+        .arg("pull")
+        .arg("--quiet")
+        .arg(image)
+        .output();
+
+    spinner.finish_and_clear();
+
+    output.map_err(|err| runtime.spawn_error(err))
+}
+
+fn is_auth_error(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    ["unauthorized", "authentication required", "access denied"]
+        .iter()
+        .any(|message| stderr.contains(message))
+}
+
+/// Ask for the credential of the registry and log in with the runtime, which saves it
+fn login(runtime: Runtime, repo: &Repository) -> Result<()> {
+    let registry = if repo.is_docker_hub() {
+        "docker.io"
+    } else {
+        repo.registry.as_str()
+    };
+
+    status!("{} requires authentication.", registry);
+
+    let username: String = dialoguer::Input::new()
+        .with_prompt("Username")
+        .interact_text()?;
+    let password = dialoguer::Password::new()
+        .with_prompt("Password")
+        .interact()?;
+
+    let mut child = Command::new(runtime.command())
+        .args([
+            "login",
+            registry,
+            "--username",
+            &username,
+            "--password-stdin",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(output::child_stdout())
+        .spawn()
+        .map_err(|err| runtime.spawn_error(err))?;
+
+    child.stdin.take().unwrap().write_all(password.as_bytes())?;
+
+    if !child.wait()?.success() {
+        bail!("Failed to log in to {}", registry);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(
+        runtime: Option<Runtime>,
+        registry_mirror: Option<&str>,
+        mirror_fallback: bool,
+        prefer_docker_hub: bool,
+    ) -> PullOptions {
+        PullOptions::new(
+            false,
+            runtime,
+            registry_mirror,
+            mirror_fallback,
+            prefer_docker_hub,
+            PullPolicy::Missing,
+            None,
+        )
+        .unwrap()
+    }
+
+    const MIRROR: Option<&str> = Some("https://mirror.example.com:5000/");
+
+    #[test]
+    fn images_without_mirror() {
+        assert_eq!(
+            opts(None, None, false, false).images("ubuntu", "22.04"),
+            ["ubuntu:22.04"]
+        );
+    }
+
+    #[test]
+    fn images_via_mirror() {
+        assert_eq!(
+            opts(None, MIRROR, false, false).images("ubuntu", "22.04"),
+            ["mirror.example.com:5000/library/ubuntu:22.04"]
+        );
+        assert_eq!(
+            opts(None, MIRROR, true, false).images("rancher/k3s", "latest"),
+            [
+                "mirror.example.com:5000/rancher/k3s:latest",
+                "rancher/k3s:latest"
+            ]
+        );
+    }
+
+    #[test]
+    fn images_prefer_docker_hub() {
+        assert_eq!(
+            opts(None, MIRROR, false, true).images("ubuntu", "22.04"),
+            [
+                "ubuntu:22.04",
+                "mirror.example.com:5000/library/ubuntu:22.04"
+            ]
+        );
+    }
+
+    #[test]
+    fn images_mirror_only_for_docker_hub() {
+        for (mirror_fallback, prefer_docker_hub) in [(false, false), (true, false), (false, true)] {
+            assert_eq!(
+                opts(None, MIRROR, mirror_fallback, prefer_docker_hub)
+                    .images("ghcr.io/owner/image", "1.0"),
+                ["ghcr.io/owner/image:1.0"]
+            );
+        }
+    }
+
+    #[test]
+    fn strategies_try_each_image_for_each_runtime() {
+        let strategies = |opts: &PullOptions| {
+            opts.strategies("alpine", "3.19")
+                .into_iter()
+                .map(|s| (s.runtime, s.image))
+                .collect::<Vec<_>>()
+        };
+
+        let mirrored = "mirror.example.com/library/alpine:3.19".to_string();
+        let hub = "alpine:3.19".to_string();
+
+        assert_eq!(
+            strategies(&opts(
+                Some(Runtime::Podman),
+                Some("mirror.example.com"),
+                true,
+                false
+            )),
+            [
+                (Runtime::Podman, mirrored.clone()),
+                (Runtime::Podman, hub.clone()),
+            ]
+        );
+        assert_eq!(
+            strategies(&opts(None, Some("mirror.example.com"), false, true)),
+            [
+                (Runtime::Docker, hub.clone()),
+                (Runtime::Docker, mirrored.clone()),
+                (Runtime::Podman, hub.clone()),
+                (Runtime::Podman, mirrored.clone()),
+                (Runtime::Nerdctl, hub),
+                (Runtime::Nerdctl, mirrored),
+            ]
+        );
+    }
+}
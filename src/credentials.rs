@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, bail, Result};
+use directories::BaseDirs;
+use serde::Deserialize;
+
+/// Key of Docker Hub in config.json, kept from the v1 API
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+/// Username and password (or token) for a registry
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub username: String,
+    pub secret: String,
+}
+
+impl Credential {
+    /// Value of the `Authorization` header for basic authentication
+    pub fn basic_auth(&self) -> String {
+        format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", self.username, self.secret))
+        )
+    }
+}
+
+/// Part of docker's config.json about authentication
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+}
+
+fn docker_config_path() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("DOCKER_CONFIG") {
+        return Ok(PathBuf::from(dir).join("config.json"));
+    }
+
+    let base_dirs = BaseDirs::new().ok_or_else(|| anyhow!("Failed to get home directory"))?;
+    Ok(base_dirs.home_dir().join(".docker").join("config.json"))
+}
+
+/// Server name used as the key of config.json and credential helpers
+pub fn server_name(registry: &str) -> &str {
+    if registry == "registry-1.docker.io" || registry == "docker.io" {
+        DOCKER_HUB_SERVER
+    } else {
+        registry
+    }
+}
+
+fn normalize(server: &str) -> &str {
+    server
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/')
+}
+
+/// Get the entry for `server`, whose key may be written with or without the scheme
+fn find_entry<'a, V>(map: &'a HashMap<String, V>, server: &str) -> Option<&'a V> {
+    map.iter()
+        .find(|(key, _)| normalize(key) == normalize(server))
+        .map(|(_, value)| value)
+}
+
+/// Find the credential for `registry` as docker does:
+/// a helper for the registry, the default credential store, then `auths`
+pub fn lookup(registry: &str) -> Result<Option<Credential>> {
+    let path = docker_config_path()?;
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let config: DockerConfig = serde_json::from_str(&fs::read_to_string(&path)?)
+        .map_err(|err| anyhow!("Failed to parse {}: {}", path.display(), err))?;
+
+    let server = server_name(registry);
+
+    if let Some(helper) = find_entry(&config.cred_helpers, server) {
+        return run_helper(helper, server);
+    }
+    if let Some(helper) = &config.creds_store {
+        if let Some(cred) = run_helper(helper, server)? {
+            return Ok(Some(cred));
+        }
+    }
+
+    match find_entry(&config.auths, server).and_then(|entry| entry.auth.as_ref()) {
+        Some(auth) => {
+            let decoded = String::from_utf8(base64::decode(auth.trim())?)?;
+            let (username, secret) = decoded
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid auth for {} in {}", server, path.display()))?;
+            Ok(Some(Credential {
+                username: username.to_string(),
+                secret: secret.to_string(),
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Ask `docker-credential-<helper>` for the credential of `server`
+fn run_helper(helper: &str, server: &str) -> Result<Option<Credential>> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct HelperOutput {
+        username: String,
+        secret: String,
+    }
+
+    let program = format!("docker-credential-{}", helper);
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("Failed to run {}: {}", program, err))?;
+
+    child.stdin.take().unwrap().write_all(server.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        // Helpers report a missing credential as an error
+        let message = String::from_utf8_lossy(&output.stdout);
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        bail!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+
+    let output: HelperOutput = serde_json::from_slice(&output.stdout)?;
+    Ok(Some(Credential {
+        username: output.username,
+        secret: output.secret,
+    }))
+}
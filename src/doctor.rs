@@ -0,0 +1,287 @@
+use std::{fs, process::Command};
+
+use serde::Serialize;
+
+use crate::{
+    container::Runtime,
+    metadata::data_root,
+    wsl::{free_space, shell_quote, sudoers_file, SystemCommandRunner, WslCli, WSL},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Some features are unavailable, but installing works
+    Warn,
+    Fail,
+}
+
+/// Result of a check. `id` is part of the JSON output, so it must not be changed.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub id: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+/// Free space below which the data directory is reported
+const LOW_SPACE: u64 = 1 << 30;
+
+pub fn run_checks() -> Vec<Check> {
+    vec![check_wsl(), check_runtime(), check_curl(), check_data_dir()]
+}
+
+fn check_wsl() -> Check {
+    let (status, detail) = match WslCli::new(Box::new(SystemCommandRunner)).version() {
+        Ok(version) => (Status::Ok, format!("WSL {}", version)),
+        Err(err) => (
+            Status::Fail,
+            format!("{:#}; install WSL or run `wsl.exe --update`", err),
+        ),
+    };
+    Check {
+        id: "wsl",
+        status,
+        detail,
+    }
+}
+
+fn check_runtime() -> Check {
+    let found = [Runtime::Docker, Runtime::Podman, Runtime::Nerdctl]
+        .iter()
+        .filter_map(|runtime| command_version(&runtime.to_string()))
+        .collect::<Vec<_>>();
+
+    let (status, detail) = if found.is_empty() {
+        (
+            Status::Warn,
+            "No container runtime found on PATH; only --from-file and --from-oci-layout work"
+                .to_string(),
+        )
+    } else {
+        (Status::Ok, found.join(", "))
+    };
+    Check {
+        id: "container-runtime",
+        status,
+        detail,
+    }
+}
+
+fn check_curl() -> Check {
+    let (status, detail) = match command_version("curl") {
+        Some(version) => (Status::Ok, version),
+        None => (
+            Status::Warn,
+            "curl not found on PATH; `tags` and --interactive-tags don't work".to_string(),
+        ),
+    };
+    Check {
+        id: "curl",
+        status,
+        detail,
+    }
+}
+
+fn check_data_dir() -> Check {
+    let result = data_root().and_then(|dir| {
+        fs::create_dir_all(&dir)?;
+        tempfile::tempfile_in(&dir)?;
+        Ok((free_space(&dir)?, dir))
+    });
+
+    let (status, detail) = match result {
+        Ok((free, dir)) if free < LOW_SPACE => (
+            Status::Warn,
+            format!(
+                "{} has only {} free",
+                dir.display(),
+                indicatif::HumanBytes(free)
+            ),
+        ),
+        Ok((free, dir)) => (
+            Status::Ok,
+            format!("{} ({} free)", dir.display(), indicatif::HumanBytes(free)),
+        ),
+        Err(err) => (
+            Status::Fail,
+            format!("Data directory is not writable: {:#}", err),
+        ),
+    };
+    Check {
+        id: "data-dir",
+        status,
+        detail,
+    }
+}
+
+/// Check that the installed distribution `name` works. `user` is the user created on installation.
+pub fn verify_distribution(wsl: &WSL, name: &str, user: Option<&str>) -> Vec<Check> {
+    let launch = match capture(wsl, name, "true") {
+        Ok(Some(_)) => Check {
+            id: "launch",
+            status: Status::Ok,
+            detail: "Distribution starts".to_string(),
+        },
+        Ok(None) => fail("launch", "A command in the distribution failed".to_string()),
+        Err(err) => fail("launch", format!("Failed to start: {:#}", err)),
+    };
+    // The other checks run commands in the distribution as well
+    if launch.status == Status::Fail {
+        return vec![launch];
+    }
+
+    let mut checks = vec![launch];
+
+    // Fields of /etc/passwd: name:password:uid:gid:gecos:home:shell
+    let passwd = wsl.get_distribution_configuration(name).and_then(|conf| {
+        let entry = capture(wsl, name, &format!("getent passwd {}", conf.default_uid))?;
+        Ok((conf.default_uid, entry))
+    });
+    let entry = match passwd {
+        Ok((uid, Some(entry))) => {
+            let fields = entry
+                .trim()
+                .split(':')
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            checks.push(match user {
+                Some(user) if fields[0] != user => fail(
+                    "default-user",
+                    format!(
+                        "Default user is {} (uid {}), but {} was created on installation",
+                        fields[0], uid, user
+                    ),
+                ),
+                _ => Check {
+                    id: "default-user",
+                    status: Status::Ok,
+                    detail: format!("{} (uid {})", fields[0], uid),
+                },
+            });
+            Some(fields)
+        }
+        Ok((uid, None)) => {
+            checks.push(fail(
+                "default-user",
+                format!("Default uid {} has no entry in /etc/passwd", uid),
+            ));
+            None
+        }
+        Err(err) => {
+            checks.push(fail("default-user", format!("{:#}", err)));
+            None
+        }
+    };
+
+    if let Some(shell) = entry.as_ref().and_then(|fields| fields.get(6)) {
+        checks.push(
+            match capture(wsl, name, &format!("test -x {}", shell_quote(shell))) {
+                Ok(Some(_)) => Check {
+                    id: "shell",
+                    status: Status::Ok,
+                    detail: shell.clone(),
+                },
+                Ok(None) => fail("shell", format!("Shell {} is not executable", shell)),
+                Err(err) => fail("shell", format!("{:#}", err)),
+            },
+        );
+    }
+
+    checks.push(match wsl.check_network(name) {
+        Ok(true) => Check {
+            id: "network",
+            status: Status::Ok,
+            detail: "Names are resolved".to_string(),
+        },
+        Ok(false) => fail(
+            "network",
+            "Failed to resolve names; reinstall with --fix-network or fix /etc/resolv.conf"
+                .to_string(),
+        ),
+        Err(err) => fail("network", format!("{:#}", err)),
+    });
+
+    let default_user = entry.as_ref().map(|fields| fields[0].as_str());
+    if let Some(default_user) = default_user.filter(|user| *user != "root") {
+        checks.push(check_sudo(wsl, name, default_user));
+    }
+
+    checks
+}
+
+/// Check that `user` can use sudo if it is in a sudo group or sudoers.d
+fn check_sudo(wsl: &WSL, name: &str, user: &str) -> Check {
+    let quoted = shell_quote(user);
+    let configured = capture(
+        wsl,
+        name,
+        &format!(
+            "id -nG {} | grep -qwE 'sudo|wheel' || test -e {}",
+            quoted,
+            sudoers_file(user)
+        ),
+    );
+
+    let (status, detail) = match configured {
+        Ok(Some(_)) => {
+            // Listing the privileges of another user requires root
+            let allowed = wsl.run(
+                name,
+                Some("root"),
+                &[],
+                &[
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    format!(
+                        "LC_ALL=C sudo -l -U {} 2>/dev/null | grep -q 'may run the following'",
+                        quoted
+                    ),
+                ],
+            );
+            match allowed {
+                Ok(0) => (Status::Ok, format!("{} can use sudo", user)),
+                Ok(_) => (
+                    Status::Fail,
+                    format!(
+                        "{} is configured for sudo, but sudo is missing or denies it",
+                        user
+                    ),
+                ),
+                Err(err) => (Status::Fail, format!("{:#}", err)),
+            }
+        }
+        Ok(None) => (Status::Ok, format!("sudo is not configured for {}", user)),
+        Err(err) => (Status::Fail, format!("{:#}", err)),
+    };
+    Check {
+        id: "sudo",
+        status,
+        detail,
+    }
+}
+
+fn fail(id: &'static str, detail: String) -> Check {
+    Check {
+        id,
+        status: Status::Fail,
+        detail,
+    }
+}
+
+/// Stdout of `command` run in the distribution, or `None` if it fails
+fn capture(wsl: &WSL, name: &str, command: &str) -> anyhow::Result<Option<String>> {
+    let output = wsl.run_capture(name, command)?;
+    Ok((output.exit_code == 0).then(|| String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// First line of `<program> --version`, if the program runs
+fn command_version(program: &str) -> Option<String> {
+    let output = Command::new(program).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Some(stdout.lines().next().unwrap_or(program).trim().to_string())
+}
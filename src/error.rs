@@ -0,0 +1,44 @@
+use std::fmt;
+
+/// Failures reported with a distinct exit code, so that scripts can branch on it
+#[derive(Debug)]
+pub enum Failure {
+    Usage(String),
+    NotFound(String),
+    AlreadyExists(String),
+    RuntimeMissing(String),
+    WslUnavailable(String),
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Usage(_) => 2,
+            Self::NotFound(_) => 3,
+            Self::AlreadyExists(_) => 4,
+            Self::RuntimeMissing(_) => 5,
+            Self::WslUnavailable(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage(message)
+            | Self::NotFound(message)
+            | Self::AlreadyExists(message)
+            | Self::RuntimeMissing(message)
+            | Self::WslUnavailable(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Exit code for the error; 1 for errors without a category
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .find_map(|err| err.downcast_ref::<Failure>())
+        .map_or(1, Failure::exit_code)
+}
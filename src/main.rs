@@ -1,252 +1,3962 @@
+mod archive;
 mod bindings;
+mod cleanup;
+mod config;
+mod container;
+mod credentials;
+mod doctor;
+mod error;
+mod history;
+mod metadata;
+mod oci;
+mod output;
+mod packages;
+mod progress;
+mod registry;
+mod sha256;
+mod template;
 mod wsl;
 
 use std::{
-    fs::{self, File},
-    io::copy,
-    path::{Path, PathBuf},
+    cell::RefCell,
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{self, BufWriter, ErrorKind, IsTerminal},
+    iter,
+    path::{Component, Path, PathBuf, Prefix},
     process::{Command, Stdio},
+    str::FromStr,
+    time::Duration,
 };
 
 use anyhow::{anyhow, bail, Result};
-use directories::BaseDirs;
-use flate2::{write::GzEncoder, Compression};
+use indicatif::HumanBytes;
 use scopeguard::defer;
+use structopt::StructOpt;
 use tempfile::NamedTempFile;
 
-use crate::wsl::WSL;
+use crate::{
+    archive::{ArchiveFormat, ImportFormat, RootfsFilter},
+    bindings::Windows::Win32::System::SubsystemForLinux::{
+        WSL_DISTRIBUTION_FLAGS, WSL_DISTRIBUTION_FLAGS_APPEND_NT_PATH,
+        WSL_DISTRIBUTION_FLAGS_ENABLE_DRIVE_MOUNTING, WSL_DISTRIBUTION_FLAGS_ENABLE_INTEROP,
+    },
+    config::{ByteSize, Config},
+    container::{get_distribution_rootfs, ExportStream, PullOptions, PullPolicy, Runtime},
+    error::Failure,
+    history::Action,
+    metadata::{cache_root, data_root, DistroMetadata, InstallStage, Metadata},
+    oci::OciLayout,
+    output::status,
+    progress::{InstallEvent, ProgressMode},
+    registry::Repository,
+    template::Template,
+    wsl::{file_system_name, free_space, mount_vhd, shell_quote, unmount_vhd, NewUser, WSL},
+};
 
 /// list installed distributions
 #[argopt::subcmd]
-fn list() -> Result<()> {
+fn list(
+    /// Show only running distributions
+    #[opt(long)]
+    running: bool,
+    ///
+    /// Print each distribution with a template (placeholders: {name}, {version}, {default_user}, {size}), or `csv`
+    #[opt(long)]
+    format: Option<String>,
+    ///
+    /// Show a table with the state, WSL version and default user like `wsl.exe --list --verbose`
+    #[opt(long, short, conflicts_with = "format")]
+    verbose: bool,
+) -> Result<()> {
     let wsl = wsl::WSL::new();
-    let distros = wsl.list_installed_distros()?;
 
-    for distro in distros {
-        // let conf = wsl.get_distribution_configuration(&distro)?;
-        println!("{}", distro);
+    if verbose {
+        return list_verbose(&wsl, running);
+    }
+    if format.as_deref() == Some("csv") {
+        return list_csv(&wsl, running);
+    }
+
+    let format = format.as_deref().map(parse_list_format).transpose()?;
+
+    // Needed for the default user, which is looked up only in running distributions
+    let running_distros = if running || format.is_some() {
+        wsl.list_running_distros()?
+    } else {
+        vec![]
+    };
+    let distros = if running {
+        running_distros.clone()
+    } else {
+        wsl.list_installed_distros()?
+    };
+
+    let metadata = Metadata::load()?;
+
+    for distro in distros {
+        let format = match &format {
+            Some(format) => format,
+            None => {
+                println!("{}", distro);
+                continue;
+            }
+        };
+
+        let mut line = String::new();
+        for part in format {
+            match part {
+                FormatPart::Text(text) => line.push_str(text),
+                FormatPart::Field(field) => line.push_str(&list_field(
+                    &wsl,
+                    &metadata,
+                    &running_distros,
+                    &distro,
+                    field,
+                )?),
+            }
+        }
+        println!("{}", line);
+    }
+
+    Ok(())
+}
+
+/// Print the table of `list --verbose`
+fn list_verbose(wsl: &WSL, running: bool) -> Result<()> {
+    let metadata = Metadata::load()?;
+    let running_distros = wsl.list_running_distros()?;
+
+    let mut rows = vec![];
+    for entry in wsl.list_verbose()? {
+        let is_running = running_distros.contains(&entry.name);
+        if running && !is_running {
+            continue;
+        }
+
+        let conf = wsl.get_distribution_configuration(&entry.name)?;
+        let version = entry.version.unwrap_or(conf.distribution_version);
+        let default_user =
+            default_user_name(wsl, &metadata, &entry.name, conf.default_uid, is_running)?;
+
+        rows.push([
+            format!(
+                "{} {}",
+                if entry.is_default { "*" } else { " " },
+                entry.name
+            ),
+            entry.state,
+            version.to_string(),
+            default_user,
+        ]);
+    }
+
+    let header = [
+        "  NAME".to_string(),
+        "STATE".to_string(),
+        "VERSION".to_string(),
+        "DEFAULT USER".to_string(),
+    ];
+    let widths = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .chain(iter::once(&header))
+                .map(|row| row[i].chars().count())
+                .max()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    for row in iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+/// Print `list --format csv`, with the fields of `--format` as columns
+fn list_csv(wsl: &WSL, running: bool) -> Result<()> {
+    let metadata = Metadata::load()?;
+    let running_distros = wsl.list_running_distros()?;
+    let distros = if running {
+        running_distros.clone()
+    } else {
+        wsl.list_installed_distros()?
+    };
+
+    println!("{}", LIST_FIELDS.join(","));
+
+    for distro in distros {
+        let conf = wsl.get_distribution_configuration(&distro)?;
+        let is_running = running_distros.contains(&distro);
+        let row = [
+            distro.clone(),
+            conf.distribution_version.to_string(),
+            default_user_name(wsl, &metadata, &distro, conf.default_uid, is_running)?,
+            list_field(wsl, &metadata, &running_distros, &distro, "size")?,
+        ];
+        println!(
+            "{}",
+            row.iter()
+                .map(|field| csv_field(field))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+    }
+
+    Ok(())
+}
+
+/// Quote a CSV field if needed, doubling the quotes in it (RFC 4180)
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Name of the default user. Looking it up in a stopped distribution would start it,
+/// so the user created by `install` (or the uid) is used instead.
+fn default_user_name(
+    wsl: &WSL,
+    metadata: &Metadata,
+    distro: &str,
+    uid: u32,
+    is_running: bool,
+) -> Result<String> {
+    if uid == 0 {
+        return Ok("root".to_string());
+    }
+
+    if is_running {
+        let output = wsl.run_capture(distro, &format!("getent passwd {} | cut -d: -f1", uid))?;
+        let name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !name.is_empty() {
+            return Ok(name);
+        }
+    }
+
+    Ok(metadata
+        .distros
+        .get(distro)
+        .and_then(|meta| meta.user.clone())
+        .unwrap_or_else(|| format!("uid {}", uid)))
+}
+
+const LIST_FIELDS: &[&str] = &["name", "version", "default_user", "size"];
+
+enum FormatPart {
+    Text(String),
+    Field(String),
+}
+
+/// Split a template like `{name}: {size}` into text and placeholders.
+/// `{{` and `}}` are literal braces.
+fn parse_list_format(format: &str) -> Result<Vec<FormatPart>> {
+    let mut ret = vec![];
+    let mut text = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let field = chars.by_ref().take_while(|&c| c != '}').collect::<String>();
+                if !LIST_FIELDS.contains(&field.as_str()) {
+                    bail!(Failure::Usage(format!(
+                        "Unknown placeholder `{{{}}}` in --format (available: {})",
+                        field,
+                        LIST_FIELDS
+                            .iter()
+                            .map(|field| format!("{{{}}}", field))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )));
+                }
+                ret.push(FormatPart::Text(std::mem::take(&mut text)));
+                ret.push(FormatPart::Field(field));
+            }
+            '}' => bail!(Failure::Usage(
+                "Unmatched `}` in --format (use `}}` for a literal brace)".to_string()
+            )),
+            c => text.push(c),
+        }
+    }
+
+    ret.push(FormatPart::Text(text));
+    Ok(ret)
+}
+
+/// Value of a placeholder of `list --format`, computed only when used.
+/// Stopped distributions are not started, as with `list --verbose`.
+fn list_field(
+    wsl: &WSL,
+    metadata: &Metadata,
+    running_distros: &[String],
+    distro: &str,
+    field: &str,
+) -> Result<String> {
+    Ok(match field {
+        "name" => distro.to_string(),
+        "version" => wsl
+            .get_distribution_configuration(distro)?
+            .distribution_version
+            .to_string(),
+        "default_user" => default_user_name(
+            wsl,
+            metadata,
+            distro,
+            wsl.get_distribution_configuration(distro)?.default_uid,
+            running_distros.iter().any(|name| name == distro),
+        )?,
+        "size" => {
+            // The registry knows where the distribution is even after it's moved
+            let dir = wsl.base_path(distro)?.or_else(|| {
+                metadata
+                    .distros
+                    .get(distro)
+                    .map(|meta| meta.data_dir.clone())
+            });
+            // WSL1 keeps the files in a directory instead of a VHD
+            let size = dir.and_then(|dir| {
+                fs::metadata(extended_length_path(&dir.join("ext4.vhdx")))
+                    .map(|meta| meta.len())
+                    .or_else(|_| dir_size(&dir.join("rootfs")))
+                    .ok()
+            });
+            size.map_or_else(|| "-".to_string(), |size| size.to_string())
+        }
+        _ => unreachable!(),
+    })
+}
+
+/// Flag set on the command line or in the profile, where `off` on the command line overrides
+/// the profile
+fn profile_flag(on: bool, off: bool, profile: bool) -> bool {
+    on || (profile && !off)
+}
+
+/// Install distribution
+#[argopt::subcmd]
+fn install(
+    /// Do not add user after installation
+    #[opt(long)]
+    no_user: bool,
+    ///
+    /// Name of a user to create instead of asking for it; repeatable, and the first one becomes the default user
+    #[opt(
+        long,
+        number_of_values = 1,
+        conflicts_with = "no-user",
+        conflicts_with = "from-wsl"
+    )]
+    user: Vec<String>,
+    ///
+    /// Read the passwords of --user from this file of `name:password` lines instead of asking for them
+    #[opt(
+        long,
+        parse(from_os_str),
+        requires = "user",
+        conflicts_with = "no-password"
+    )]
+    password_file: Option<PathBuf>,
+    ///
+    /// Register an existing ext4.vhdx in place instead of pulling an image
+    #[opt(long, parse(from_os_str))]
+    import_in_place: Option<PathBuf>,
+    ///
+    /// Install from a rootfs tarball file instead of pulling an image
+    #[opt(long, parse(from_os_str), conflicts_with = "import-in-place")]
+    from_file: Option<PathBuf>,
+    ///
+    /// Install from an extracted rootfs directory, which is archived with root as the owner
+    #[opt(
+        long,
+        parse(from_os_str),
+        conflicts_with = "import-in-place",
+        conflicts_with = "from-file"
+    )]
+    from_dir: Option<PathBuf>,
+    ///
+    /// Install from an OCI image layout directory (e.g. created by buildah)
+    #[opt(
+        long,
+        parse(from_os_str),
+        conflicts_with = "import-in-place",
+        conflicts_with = "from-file",
+        conflicts_with = "from-dir"
+    )]
+    from_oci_layout: Option<PathBuf>,
+    ///
+    /// Build an image from this Dockerfile and install it
+    #[opt(
+        long,
+        parse(from_os_str),
+        conflicts_with = "import-in-place",
+        conflicts_with = "from-file",
+        conflicts_with = "from-dir",
+        conflicts_with = "from-oci-layout"
+    )]
+    dockerfile: Option<PathBuf>,
+    ///
+    /// Install a copy of this installed distribution, keeping its users and settings
+    #[opt(
+        long,
+        conflicts_with = "import-in-place",
+        conflicts_with = "from-file",
+        conflicts_with = "from-dir",
+        conflicts_with = "from-oci-layout",
+        conflicts_with = "dockerfile"
+    )]
+    from_wsl: Option<String>,
+    ///
+    /// Build-time variable for --dockerfile (KEY=VALUE); repeatable
+    #[opt(long, number_of_values = 1, requires = "dockerfile")]
+    build_arg: Vec<String>,
+    ///
+    /// Keep the image built from --dockerfile
+    #[opt(long, requires = "dockerfile")]
+    keep_image: bool,
+    ///
+    /// Remove character and block devices (e.g. in /dev) from the rootfs before importing
+    #[opt(
+        long,
+        conflicts_with = "import-in-place",
+        conflicts_with = "from-file",
+        conflicts_with = "from-dir",
+        conflicts_with = "from-oci-layout",
+        conflicts_with = "from-wsl"
+    )]
+    exclude_dev: bool,
+    ///
+    /// Pipe the exported rootfs to `wsl.exe --import` without a temporary file (ignores the rootfs cache)
+    #[opt(
+        long,
+        conflicts_with = "import-in-place",
+        conflicts_with = "from-file",
+        conflicts_with = "from-dir",
+        conflicts_with = "from-oci-layout",
+        conflicts_with = "from-wsl",
+        conflicts_with = "dockerfile",
+        conflicts_with = "exclude-dev"
+    )]
+    no_persist: bool,
+    ///
+    /// Do not enable systemd even if the image uses it
+    #[opt(long)]
+    no_systemd: bool,
+    ///
+    /// Enable systemd if the image uses it, even if the profile sets `no-systemd`
+    #[opt(long, conflicts_with = "no-systemd")]
+    systemd: bool,
+    ///
+    /// Seconds to wait for systemd to finish booting after enabling it
+    #[opt(long, default_value = "120")]
+    systemd_timeout: u64,
+    ///
+    /// Seconds to wait for names to be resolved with --fix-network, before fixing them
+    #[opt(long, default_value = "6")]
+    network_timeout: u64,
+    ///
+    /// Seconds between checks while waiting for systemd or the network
+    #[opt(long, default_value = "1")]
+    poll_interval: u64,
+    ///
+    /// Hostname of the distribution (defaults to the one of Windows)
+    #[opt(long, conflicts_with = "import-in-place")]
+    hostname: Option<String>,
+    ///
+    /// WSL version of the distribution (1 or 2; defaults to `wsl.exe --set-default-version`)
+    #[opt(long, conflicts_with = "import-in-place")]
+    version: Option<u32>,
+    ///
+    /// Run this command instead of a shell when the distribution is opened (e.g. a service)
+    #[opt(long, conflicts_with = "import-in-place")]
+    default_command: Option<String>,
+    ///
+    /// Check the network after installation and write /etc/resolv.conf if names can't be resolved
+    #[opt(long)]
+    fix_network: bool,
+    ///
+    /// Do not check the network, even if the profile sets `fix-network`
+    #[opt(long, conflicts_with = "fix-network")]
+    no_fix_network: bool,
+    ///
+    /// Show docker's progress output
+    #[opt(long, short)]
+    verbose: bool,
+    ///
+    /// Container runtime to use (docker, podman or nerdctl; tries them in order by default)
+    #[opt(long)]
+    runtime: Option<Runtime>,
+    ///
+    /// Pull images via this registry mirror (e.g. mirror.example.com:5000)
+    #[opt(long)]
+    registry_mirror: Option<String>,
+    ///
+    /// Pull from the original registry if the mirror fails
+    #[opt(long, requires = "registry-mirror")]
+    mirror_fallback: bool,
+    ///
+    /// Do not pull from the original registry, even if the profile sets `mirror-fallback`
+    #[opt(long, conflicts_with = "mirror-fallback")]
+    no_mirror_fallback: bool,
+    ///
+    /// Pull from Docker Hub first, and use the registry mirror only if it fails
+    #[opt(long, requires = "registry-mirror", conflicts_with = "mirror-fallback")]
+    prefer_docker_hub: bool,
+    ///
+    /// Pull from the registry mirror first, even if the profile sets `prefer-docker-hub`
+    #[opt(long, conflicts_with = "prefer-docker-hub")]
+    prefer_mirror: bool,
+    ///
+    /// HTTP proxy for pulling images and registry requests (e.g. http://proxy.example.com:8080)
+    #[opt(long)]
+    proxy: Option<String>,
+    ///
+    /// Comma-separated hosts to access without the proxy (e.g. registry.internal,.corp.example.com)
+    #[opt(long)]
+    no_proxy: Option<String>,
+    ///
+    /// Free space to keep on the cache volume, removing old cached rootfs (e.g. 10G)
+    #[opt(long)]
+    min_free_space: Option<ByteSize>,
+    ///
+    /// When to pull the image instead of using the cached rootfs (always, missing or never)
+    #[opt(long, default_value = "missing")]
+    pull_policy: PullPolicy,
+    ///
+    /// Name of the data directory (defaults to the installing name)
+    #[opt(long)]
+    data_dir_name: Option<String>,
+    ///
+    /// Prepend this to the installing name (e.g. `team-` for team-ubuntu-22.04)
+    #[opt(long)]
+    prefix: Option<String>,
+    ///
+    /// Home directory of the new user (defaults to /home/<user>)
+    #[opt(long)]
+    user_home: Option<String>,
+    ///
+    /// Full name of the new user (stored as the GECOS comment)
+    #[opt(long)]
+    full_name: Option<String>,
+    ///
+    /// Create the user without password (no password prompt)
+    #[opt(long)]
+    no_password: bool,
+    ///
+    /// Set the password of the user, even if the profile sets `no-password`
+    #[opt(long, conflicts_with = "no-password")]
+    ask_password: bool,
+    ///
+    /// Require the password to be at least this many characters
+    #[opt(long)]
+    min_password_length: Option<usize>,
+    ///
+    /// Allow the user to use sudo without password
+    #[opt(long)]
+    nopasswd_sudo: bool,
+    ///
+    /// Require the password for sudo, even if the profile sets `nopasswd-sudo`
+    #[opt(long, conflicts_with = "nopasswd-sudo")]
+    sudo_password: bool,
+    ///
+    /// Do not add the user to the wheel or sudo group
+    #[opt(long)]
+    no_group_add: bool,
+    ///
+    /// Add the user to the wheel or sudo group, even if the profile sets `no-group-add`
+    #[opt(long, conflicts_with = "no-group-add")]
+    group_add: bool,
+    ///
+    /// Extra argument to useradd (e.g. --useradd-arg=--comment=Developer); repeatable
+    #[opt(long, allow_hyphen_values = true, number_of_values = 1)]
+    useradd_arg: Vec<String>,
+    ///
+    /// Install even if the disk seems to be short of free space
+    #[opt(long)]
+    force: bool,
+    ///
+    /// Continue a previous installation that failed halfway
+    #[opt(long)]
+    resume: bool,
+    ///
+    /// Do nothing if the distribution is already installed, instead of failing
+    #[opt(long)]
+    skip_existing: bool,
+    ///
+    /// Directory for temporary rootfs tarballs (defaults to $TMPDIR or the system temp directory)
+    #[opt(long, parse(from_os_str))]
+    temp_dir: Option<PathBuf>,
+    ///
+    /// Compression of the tarball given to `wsl.exe --import` (none or gzip; defaults to the fastest one WSL supports)
+    #[opt(long)]
+    import_format: Option<ImportFormat>,
+    ///
+    /// Abort importing the rootfs if it takes longer than this (in seconds)
+    #[opt(long)]
+    import_timeout: Option<u64>,
+    ///
+    /// Retry importing this many times after `wsl.exe --shutdown` if the WSL service fails
+    #[opt(long, default_value = "1")]
+    retry_import: u32,
+    ///
+    /// Choose the tag from the list on the registry when it is not given
+    #[opt(long)]
+    interactive_tags: bool,
+    ///
+    /// Leave the distribution registered if the installation fails after registering it
+    #[opt(long)]
+    no_rollback: bool,
+    ///
+    /// Make the installed distribution the default one
+    #[opt(long)]
+    set_default: bool,
+    ///
+    /// Print the result as JSON to stdout
+    #[opt(long)]
+    json: bool,
+    ///
+    /// How to report progress (console, or json for newline-delimited JSON events to stderr)
+    #[opt(long, default_value = "console")]
+    progress: ProgressMode,
+    ///
+    /// Apply a set of options defined as [profile.<name>] in wsl-get.toml
+    #[opt(long)]
+    profile: Option<String>,
+    ///
+    /// Name of distribution to install (e.g. ubuntu, ubuntu:20.04)
+    distro: String,
+    ///
+    /// Installing name
+    install_name: Option<String>,
+) -> Result<()> {
+    output::set_json(json);
+    progress::set_mode(progress);
+
+    // Options given on the command line take precedence over the profile
+    let config = Config::load()?;
+    let profile = match &profile {
+        Some(name) => config.profile(name)?,
+        None => &Default::default(),
+    };
+    let runtime = runtime.or(profile.runtime);
+    let registry_mirror = registry_mirror.or_else(|| profile.registry_mirror.clone());
+    let mirror_fallback =
+        profile_flag(mirror_fallback, no_mirror_fallback, profile.mirror_fallback);
+    let prefer_docker_hub =
+        profile_flag(prefer_docker_hub, prefer_mirror, profile.prefer_docker_hub);
+    let data_dir_name = data_dir_name.or_else(|| profile.data_dir_name.clone());
+    let prefix = prefix.or_else(|| profile.prefix.clone());
+    let user_home = user_home.or_else(|| profile.user_home.clone());
+    let no_password = profile_flag(no_password, ask_password, profile.no_password);
+    let nopasswd_sudo = profile_flag(nopasswd_sudo, sudo_password, profile.nopasswd_sudo);
+    let no_group_add = profile_flag(no_group_add, group_add, profile.no_group_add);
+    let no_systemd = profile_flag(no_systemd, systemd, profile.no_systemd);
+    let fix_network = profile_flag(fix_network, no_fix_network, profile.fix_network);
+    // A registered VHD is used as it is, as with the command line options
+    let hostname = hostname.or_else(|| {
+        profile
+            .hostname
+            .clone()
+            .filter(|_| import_in_place.is_none())
+    });
+    let default_command = default_command.or_else(|| {
+        profile
+            .default_command
+            .clone()
+            .filter(|_| import_in_place.is_none())
+    });
+    let proxy = proxy.or_else(|| profile.proxy.clone());
+    let no_proxy = no_proxy.or_else(|| profile.no_proxy.clone());
+    set_proxy(proxy.as_deref(), no_proxy.as_deref());
+    let useradd_arg = if useradd_arg.is_empty() {
+        profile.useradd_args.clone()
+    } else {
+        useradd_arg
+    };
+
+    let wsl = WSL::new();
+
+    // Configuration of the distribution to copy, whose users come along with the rootfs
+    let source_conf = match &from_wsl {
+        Some(source) => {
+            if !wsl.is_distribution_registered(source) {
+                bail!(Failure::NotFound(format!(
+                    "Distribution {} is not installed",
+                    source
+                )));
+            }
+            Some(wsl.get_distribution_configuration(source)?)
+        }
+        None => None,
+    };
+    let no_user = no_user || from_wsl.is_some();
+
+    // Follow wsl.exe, which uses WSL2 unless the default version is set
+    let version = match version {
+        Some(version @ (1 | 2)) => version,
+        Some(version) => bail!(Failure::Usage(format!(
+            "WSL version must be 1 or 2, not {}",
+            version
+        ))),
+        None => match &source_conf {
+            Some(conf) => conf.distribution_version,
+            None => wsl.default_version()?.unwrap_or(2),
+        },
+    };
+
+    let pull_opts = PullOptions::new(
+        verbose,
+        runtime,
+        registry_mirror.as_deref(),
+        mirror_fallback,
+        prefer_docker_hub,
+        pull_policy,
+        min_free_space.or(config.min_free_space).map(|size| size.0),
+    )?;
+
+    if let Some(user_home) = &user_home {
+        check_user_home(user_home)?;
+    }
+
+    for name in &user {
+        check_user_name(name)?;
+    }
+    if user.len() > 1 && (user_home.is_some() || full_name.is_some()) {
+        bail!(Failure::Usage(
+            "--user-home and --full-name cannot be used with more than one --user".to_string()
+        ));
+    }
+    if let Some(dup) = user
+        .iter()
+        .enumerate()
+        .find_map(|(i, name)| user[..i].contains(name).then_some(name))
+    {
+        bail!(Failure::Usage(format!("User {} is given twice", dup)));
+    }
+
+    // Read in advance to fail before pulling the image
+    let mut passwords = match &password_file {
+        Some(path) => read_password_file(path, &user, min_password_length)?,
+        None => Default::default(),
+    };
+
+    if let Some(hostname) = &hostname {
+        check_hostname(hostname)?;
+    }
+    if let Some(data_dir_name) = &data_dir_name {
+        check_data_dir_name(data_dir_name)?;
+    }
+
+    let pulling = import_in_place.is_none()
+        && from_file.is_none()
+        && from_dir.is_none()
+        && from_oci_layout.is_none()
+        && dockerfile.is_none()
+        && from_wsl.is_none();
+
+    let distro = if pulling && interactive_tags && !distro.contains(':') {
+        format!("{}:{}", distro, select_tag(&distro))
+    } else {
+        distro
+    };
+
+    // Without pulling an image, the positional argument is the installing name.
+    let install_name = if !pulling {
+        if install_name.is_some() {
+            bail!(Failure::Usage(
+                "Installing name must not be given twice when not pulling an image".to_string()
+            ));
+        }
+        distro.clone()
+    } else {
+        let (distro_name, distro_tag) = parse_distro_name(&distro)?;
+        install_name.unwrap_or_else(|| format!("{}-{}", sanitize_path(&distro_name), distro_tag))
+    };
+    let install_name = format!("{}{}", prefix.unwrap_or_default(), install_name);
+
+    check_install_name(&install_name)?;
+
+    let metadata = Metadata::load()?;
+
+    // Stage reached by a previous installation, if any
+    let stage = if wsl.is_distribution_registered(&install_name) {
+        match metadata.distros.get(&install_name).map(|m| m.stage) {
+            Some(stage) if stage != InstallStage::Complete => {
+                if !resume {
+                    bail!(
+                        "Distribution `{}` is not completely installed. Use --resume to continue",
+                        install_name
+                    );
+                }
+                progress::emit(InstallEvent::Resuming {
+                    install_name: &install_name,
+                });
+                Some(stage)
+            }
+            _ if skip_existing => {
+                status!("Skipping {}: already registered", install_name);
+                return Ok(());
+            }
+            _ => bail!(Failure::AlreadyExists(format!(
+                "Distribution `{}` is already registered",
+                install_name
+            ))),
+        }
+    } else {
+        if let Some(existing) = wsl.find_case_conflict(&install_name)? {
+            bail!(Failure::AlreadyExists(format!(
+                "Distribution `{}` is already registered, and WSL doesn't distinguish `{}` from it. Choose another name",
+                existing, install_name
+            )));
+        }
+        None
+    };
+
+    // Asking for the new user is the only interactive part of the installation,
+    // so that installing with --no-user works without a terminal.
+    let needs_users = !no_user && stage < Some(InstallStage::UserCreated);
+    if needs_user_prompt(needs_users, &user, no_password, password_file.as_deref())
+        && !io::stdin().is_terminal()
+    {
+        bail!("Cannot ask for the new user since stdin is not a terminal. Use --no-user to skip creating a user, or --user with --password-file or --no-password");
+    }
+
+    // Importing from stdin needs a recent WSL, so fall back to a temporary file otherwise
+    let no_persist = no_persist && stage.is_none() && {
+        let supported = wsl.supports_import_from_stdin();
+        if !supported {
+            progress::emit(InstallEvent::Warning {
+                message: "this version of WSL can't import from stdin; using a temporary file",
+            });
+        }
+        supported
+    };
+    if no_persist && pull_policy == PullPolicy::Never {
+        bail!(Failure::Usage(
+            "--no-persist always pulls the image, so it cannot be used with --pull-policy never"
+                .to_string()
+        ));
+    }
+
+    // Compressing takes longer than importing the larger tarball, so prefer no compression
+    let supported_formats = wsl.import_formats();
+    let import_format = match import_format {
+        Some(format) if !supported_formats.contains(&format) => bail!(Failure::Usage(format!(
+            "This version of WSL can't import the {} format",
+            format.name()
+        ))),
+        Some(format) => format,
+        None => ImportFormat::fastest(&supported_formats),
+    };
+    let import_archive = import_format.archive_format();
+
+    // Registering an existing VHD, resuming or --no-persist doesn't need temporary files
+    let temp_dir = if stage.is_none() && import_in_place.is_none() && !no_persist {
+        temp_dir_for_rootfs(temp_dir, force)?
+    } else {
+        PathBuf::new()
+    };
+
+    let dev_filter = RootfsFilter {
+        exclude_dev: true,
+        ..Default::default()
+    };
+
+    let mut digest = None;
+    let mut source_user = None;
+    let mut export_stream = None;
+    // Temporary tarball is left if interrupted, since `TempPath` removes it on drop
+    let mut _temp_guard = None;
+
+    let (tarball, _temp_path, image) = if stage.is_some() {
+        (None, None, None)
+    } else if let Some(vhdx) = &import_in_place {
+        if data_dir_name.is_some() {
+            bail!(Failure::Usage(
+                "--data-dir-name cannot be used with --import-in-place".to_string()
+            ));
+        }
+        if !vhdx.is_file() {
+            bail!(Failure::NotFound(format!(
+                "VHD file `{}` does not exist",
+                vhdx.display()
+            )));
+        }
+
+        progress::emit(InstallEvent::Installing {
+            source: &vhdx.display().to_string(),
+            install_name: &install_name,
+        });
+
+        (None, None, None)
+    } else if let Some(file) = &from_file {
+        if !file.is_file() {
+            bail!(Failure::NotFound(format!(
+                "Rootfs file `{}` does not exist",
+                file.display()
+            )));
+        }
+
+        progress::emit(InstallEvent::Installing {
+            source: &file.display().to_string(),
+            install_name: &install_name,
+        });
+
+        let temp_path = archive::prepare_for_import(file, &temp_dir)?;
+        _temp_guard = temp_path.as_deref().map(cleanup::remove_on_interrupt);
+        let tarball = temp_path
+            .as_ref()
+            .map_or_else(|| file.clone(), |path| path.to_path_buf());
+
+        (Some(tarball), temp_path, None)
+    } else if let Some(dir) = &from_dir {
+        if !dir.is_dir() {
+            bail!(Failure::NotFound(format!(
+                "Directory `{}` does not exist",
+                dir.display()
+            )));
+        }
+        // e.g. the parent of the rootfs directory is given by mistake
+        if !dir.join("etc").is_dir() {
+            progress::emit(InstallEvent::Warning {
+                message: &format!(
+                    "`{}` has no etc directory and may not be a rootfs",
+                    dir.display()
+                ),
+            });
+        }
+
+        progress::emit(InstallEvent::Installing {
+            source: &dir.display().to_string(),
+            install_name: &install_name,
+        });
+
+        progress::emit(InstallEvent::Exporting {
+            source: Some(&dir.display().to_string()),
+        });
+
+        let rootfs_path = tempfile::Builder::new()
+            .suffix(import_archive.extension())
+            .tempfile_in(&temp_dir)?
+            .into_temp_path();
+        _temp_guard = Some(cleanup::remove_on_interrupt(&rootfs_path));
+        let skipped = archive::archive_dir(
+            dir,
+            BufWriter::new(fs::File::create(&rootfs_path)?),
+            import_archive,
+            None,
+        )?;
+        if skipped > 0 {
+            progress::emit(InstallEvent::Warning {
+                message: &format!(
+                    "skipped {} entries that are neither files, directories nor symlinks",
+                    skipped
+                ),
+            });
+        }
+        progress::emit(InstallEvent::Exported {
+            bytes: fs::metadata(&rootfs_path)?.len(),
+        });
+
+        (Some(rootfs_path.to_path_buf()), Some(rootfs_path), None)
+    } else if let Some(dir) = &from_oci_layout {
+        let layout = OciLayout::open(dir)?;
+        let layers = layout.layers()?;
+
+        progress::emit(InstallEvent::Installing {
+            source: &dir.display().to_string(),
+            install_name: &install_name,
+        });
+
+        progress::emit(InstallEvent::Flattening {
+            layers: layers.len(),
+        });
+
+        let mut temp_file = tempfile::Builder::new()
+            .suffix(ArchiveFormat::Tar.extension())
+            .tempfile_in(&temp_dir)?;
+        _temp_guard = Some(cleanup::remove_on_interrupt(temp_file.path()));
+        oci::flatten_layers(&layers, BufWriter::new(&mut temp_file))?;
+        let temp_path = temp_file.into_temp_path();
+
+        (Some(temp_path.to_path_buf()), Some(temp_path), None)
+    } else if let Some(source) = &from_wsl {
+        progress::emit(InstallEvent::Installing {
+            source,
+            install_name: &install_name,
+        });
+
+        progress::emit(InstallEvent::Exporting {
+            source: Some(source),
+        });
+
+        let temp_path = tempfile::Builder::new()
+            .suffix(ArchiveFormat::Tar.extension())
+            .tempfile_in(&temp_dir)?
+            .into_temp_path();
+        _temp_guard = Some(cleanup::remove_on_interrupt(&temp_path));
+        wsl.export_distribution(source, &temp_path)?;
+        progress::emit(InstallEvent::Exported {
+            bytes: fs::metadata(&temp_path)?.len(),
+        });
+
+        let source_meta = metadata.distros.get(source);
+        digest = source_meta.and_then(|m| m.digest.clone());
+        source_user = source_meta.and_then(|m| m.user.clone());
+
+        (
+            Some(temp_path.to_path_buf()),
+            Some(temp_path),
+            source_meta.and_then(|m| m.image.clone()),
+        )
+    } else if let Some(dockerfile) = &dockerfile {
+        if !dockerfile.is_file() {
+            bail!(Failure::NotFound(format!(
+                "Dockerfile `{}` does not exist",
+                dockerfile.display()
+            )));
+        }
+
+        progress::emit(InstallEvent::Installing {
+            source: &dockerfile.display().to_string(),
+            install_name: &install_name,
+        });
+
+        progress::emit(InstallEvent::Building);
+
+        let runtime = runtime.unwrap_or(Runtime::Docker);
+        let tag = format!("wsl-get-build-{}", std::process::id());
+        container::build_image(runtime, dockerfile, &build_arg, &tag, verbose)?;
+
+        let _image_guard = (!keep_image).then(|| {
+            let tag = tag.clone();
+            cleanup::on_exit(move || container::remove_image(runtime, &tag))
+        });
+
+        let rootfs_path = tempfile::Builder::new()
+            .suffix(import_archive.extension())
+            .tempfile_in(&temp_dir)?
+            .into_temp_path();
+        _temp_guard = Some(cleanup::remove_on_interrupt(&rootfs_path));
+        if exclude_dev {
+            get_filtered_rootfs(&rootfs_path, import_archive, None, &dev_filter, |tar| {
+                container::export_rootfs(runtime, &tag, tar, ArchiveFormat::Tar, None, verbose)?;
+                Ok(None)
+            })?;
+        } else {
+            container::export_rootfs(runtime, &tag, &rootfs_path, import_archive, None, verbose)?;
+        }
+
+        (
+            Some(rootfs_path.to_path_buf()),
+            Some(rootfs_path),
+            Some(tag.clone()).filter(|_| keep_image),
+        )
+    } else if no_persist {
+        let (distro_name, distro_tag) = parse_distro_name(&distro)?;
+
+        progress::emit(InstallEvent::Installing {
+            source: &format!("{}:{}", distro_name, distro_tag),
+            install_name: &install_name,
+        });
+
+        // Imported directly from the output of `docker export` on registering
+        export_stream = Some(ExportStream::start(&distro_name, &distro_tag, &pull_opts)?);
+
+        (None, None, Some(format!("{}:{}", distro_name, distro_tag)))
+    } else {
+        let (distro_name, distro_tag) = parse_distro_name(&distro)?;
+
+        progress::emit(InstallEvent::Installing {
+            source: &format!("{}:{}", distro_name, distro_tag),
+            install_name: &install_name,
+        });
+
+        let rootfs_path = tempfile::Builder::new()
+            .suffix(import_archive.extension())
+            .tempfile_in(&temp_dir)?
+            .into_temp_path();
+        _temp_guard = Some(cleanup::remove_on_interrupt(&rootfs_path));
+
+        digest = if exclude_dev {
+            get_filtered_rootfs(&rootfs_path, import_archive, None, &dev_filter, |tar| {
+                get_distribution_rootfs(
+                    &distro_name,
+                    &distro_tag,
+                    tar,
+                    ArchiveFormat::Tar,
+                    None,
+                    &pull_opts,
+                )
+            })?
+        } else {
+            get_distribution_rootfs(
+                &distro_name,
+                &distro_tag,
+                &rootfs_path,
+                import_archive,
+                None,
+                &pull_opts,
+            )?
+        };
+
+        (
+            Some(rootfs_path.to_path_buf()),
+            Some(rootfs_path),
+            Some(format!("{}:{}", distro_name, distro_tag)),
+        )
+    };
+
+    let new_users = if !needs_users {
+        vec![]
+    } else if user.is_empty() {
+        let (user_name, password) = prompt_user(no_password, min_password_length)?;
+
+        vec![NewUser {
+            name: user_name,
+            password,
+            root_password: true,
+            home: user_home,
+            full_name,
+            nopasswd_sudo,
+            no_group_add,
+            useradd_args: useradd_arg,
+        }]
+    } else {
+        let mut new_users = vec![];
+        for (i, name) in user.iter().enumerate() {
+            let password = if no_password {
+                None
+            } else if password_file.is_some() {
+                passwords.remove(name)
+            } else {
+                Some(prompt_password(
+                    &format!("New password for {}", name),
+                    min_password_length,
+                )?)
+            };
+
+            new_users.push(NewUser {
+                name: name.clone(),
+                password,
+                // root shares the password of the default user, as with a single user
+                root_password: i == 0,
+                home: user_home.clone(),
+                full_name: full_name.clone(),
+                nopasswd_sudo,
+                no_group_add,
+                useradd_args: useradd_arg.clone(),
+            });
+        }
+        new_users
+    };
+
+    // Whether the distribution was registered in this run, and the directory created for it,
+    // to undo them on failure
+    let registered = RefCell::new(false);
+    let created_dir: RefCell<Option<PathBuf>> = RefCell::new(None);
+    let complete = RefCell::new(false);
+
+    defer! {
+        if !*complete.borrow() && *registered.borrow() {
+            rollback_install(&wsl, &install_name, created_dir.borrow().as_deref());
+        }
+    }
+
+    if stage.is_none() {
+        let distro_dir = match &import_in_place {
+            Some(vhdx) => vhdx.canonicalize()?.parent().unwrap().to_owned(),
+            None => data_root()?.join(data_dir_name.as_ref().unwrap_or(&install_name)),
+        };
+
+        // Never import into (and roll back) a directory holding something else
+        let dir_created = import_in_place.is_none() && {
+            let long_distro_dir = extended_length_path(&distro_dir);
+            if long_distro_dir.exists() && fs::read_dir(&long_distro_dir)?.next().is_some() {
+                bail!(Failure::AlreadyExists(format!(
+                    "Data directory {} already exists and is not empty",
+                    distro_dir.display()
+                )));
+            }
+            !long_distro_dir.exists()
+        };
+
+        progress::emit(InstallEvent::Registering);
+
+        match (&import_in_place, &tarball) {
+            (Some(vhdx), _) => wsl.import_in_place(&install_name, vhdx)?,
+            (None, Some(tarball)) => {
+                let long_distro_dir = extended_length_path(&distro_dir);
+                fs::create_dir_all(&long_distro_dir)?;
+
+                // The VHD grows at least to the size of the extracted rootfs
+                let required = archive::uncompressed_size(tarball)?;
+                let free = free_space(&distro_dir)?;
+                if free < required {
+                    let message = format!(
+                        "{} is required to import the rootfs, but only {} is free on the volume of {}",
+                        HumanBytes(required),
+                        HumanBytes(free),
+                        distro_dir.display()
+                    );
+                    if !force {
+                        bail!("{}. Use --force to install anyway", message);
+                    }
+                    progress::emit(InstallEvent::Warning { message: &message });
+                }
+
+                wsl.register_distribution(
+                    &install_name,
+                    &long_distro_dir,
+                    &extended_length_path(tarball),
+                    version,
+                    import_timeout.map(Duration::from_secs),
+                    retry_import,
+                )?;
+            }
+            (None, None) => {
+                let mut stream = export_stream.take().unwrap();
+                let long_distro_dir = extended_length_path(&distro_dir);
+                fs::create_dir_all(&long_distro_dir)?;
+
+                wsl.register_distribution_from_stdin(
+                    &install_name,
+                    &long_distro_dir,
+                    stream.stdout(),
+                    version,
+                    import_timeout.map(Duration::from_secs),
+                )?;
+                digest = stream.finish()?;
+            }
+        }
+
+        // Unregistering removes the VHD, which must not happen for --import-in-place
+        if !no_rollback && import_in_place.is_none() {
+            *registered.borrow_mut() = true;
+            if dir_created {
+                *created_dir.borrow_mut() = Some(distro_dir.clone());
+            }
+        }
+
+        let mut metadata = Metadata::load()?;
+        metadata.distros.insert(
+            install_name.clone(),
+            DistroMetadata {
+                data_dir: distro_dir,
+                image,
+                digest,
+                stage: InstallStage::Registered,
+                user: None,
+            },
+        );
+        metadata.save()?;
+
+        if wsl.is_systemd_image(&install_name)? {
+            if no_systemd {
+                progress::emit(InstallEvent::Warning {
+                    message: "this image uses systemd, but it is not enabled.\n\
+                              To enable it, add the following to /etc/wsl.conf:\n\n\
+                              [boot]\n\
+                              systemd=true\n",
+                });
+            } else {
+                progress::emit(InstallEvent::EnablingSystemd);
+                wsl.enable_systemd(&install_name)?;
+                wait_for_systemd(
+                    &wsl,
+                    &install_name,
+                    Duration::from_secs(systemd_timeout),
+                    Duration::from_secs(poll_interval),
+                )?;
+            }
+        }
+
+        if let Some(hostname) = &hostname {
+            progress::emit(InstallEvent::SettingHostname { hostname });
+            wsl.set_hostname(&install_name, hostname)?;
+        }
+
+        if let Some(command) = &default_command {
+            progress::emit(InstallEvent::SettingDefaultCommand { command });
+            wsl.set_default_command(&install_name, command)?;
+        }
+
+        if fix_network {
+            check_network(
+                &wsl,
+                &install_name,
+                Duration::from_secs(network_timeout),
+                Duration::from_secs(poll_interval),
+            )?;
+        }
+
+        // Importing makes root the default user, so take over the one of the source
+        if let Some(conf) = &source_conf {
+            wsl.configure_distribution(
+                &install_name,
+                conf.default_uid,
+                conf.wsl_distribution_flags,
+            )?;
+            Metadata::update(&install_name, |m| m.user = source_user.clone())?;
+        }
+    }
+
+    if !new_users.is_empty() {
+        // Users created before a failure are removed, even when the distribution is kept.
+        // The list is cleared once all of them are recorded as created.
+        let created_users: RefCell<Vec<&str>> = RefCell::new(vec![]);
+
+        defer! {
+            for name in created_users.borrow().iter().rev() {
+                if let Err(err) = wsl.remove_user(&install_name, name) {
+                    eprintln!("Failed to remove user {}: {:#}", name, err);
+                }
+            }
+        }
+
+        for user in &new_users {
+            progress::emit(InstallEvent::CreatingUser { name: &user.name });
+            wsl.create_user(&install_name, user)?;
+            created_users.borrow_mut().push(&user.name);
+        }
+
+        Metadata::update(&install_name, |m| {
+            m.stage = InstallStage::UserCreated;
+            m.user = Some(new_users[0].name.clone());
+        })?;
+        created_users.borrow_mut().clear();
+    }
+
+    if !no_user {
+        let user_name = match new_users.first() {
+            Some(user) => user.name.clone(),
+            None => Metadata::load()?
+                .distros
+                .get(&install_name)
+                .and_then(|m| m.user.clone())
+                .ok_or_else(|| anyhow!("Failed to find the user created before"))?,
+        };
+
+        let uid = wsl.query_uid(&install_name, &user_name)?;
+
+        wsl.set_default_uid(&install_name, uid as _)?;
+    }
+
+    Metadata::update(&install_name, |m| m.stage = InstallStage::Complete)?;
+    *complete.borrow_mut() = true;
+
+    // Restart the distribution so that changes in wsl.conf take effect
+    wsl.terminate_distribution(&install_name)?;
+
+    if set_default {
+        wsl.set_default_distribution(&install_name)?;
+        progress::emit(InstallEvent::SetDefault {
+            install_name: &install_name,
+        });
+    }
+
+    let metadata = Metadata::load()?;
+    let distro = &metadata.distros[&install_name];
+
+    history::record(
+        Action::Install,
+        &install_name,
+        distro.image.clone(),
+        distro.digest.clone(),
+    );
+
+    if json {
+        let conf = wsl.get_distribution_configuration(&install_name)?;
+
+        println!(
+            "{}",
+            serde_json::json!({
+                "install_name": install_name,
+                "image": distro.image,
+                "digest": distro.digest,
+                "install_dir": distro.data_dir,
+                "default_uid": conf.default_uid,
+            })
+        );
+    } else {
+        progress::emit(InstallEvent::Complete);
+    }
+
+    Ok(())
+}
+
+/// Restart the distribution to boot systemd, and wait until its units have settled
+fn wait_for_systemd(
+    wsl: &WSL,
+    install_name: &str,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<()> {
+    wsl.terminate_distribution(install_name)?;
+
+    progress::emit(InstallEvent::WaitingForSystemd);
+
+    if wsl.wait_for_systemd(install_name, timeout, interval)? {
+        return Ok(());
+    }
+
+    let state = wsl.systemd_state(install_name)?;
+    progress::emit(InstallEvent::Warning {
+        message: &format!(
+            "systemd is still {} after {} seconds; it may not be ready yet",
+            if state.is_empty() { "unknown" } else { &state },
+            timeout.as_secs()
+        ),
+    });
+    Ok(())
+}
+
+/// Wait a little for the network to come up, and fall back to static nameservers if it doesn't
+fn check_network(
+    wsl: &WSL,
+    install_name: &str,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<()> {
+    status!("Checking network...");
+
+    if wsl.wait_for_network(install_name, timeout, interval)? {
+        return Ok(());
+    }
+
+    status!("Failed to resolve names. Writing /etc/resolv.conf...");
+    wsl.fix_network(install_name)?;
+
+    if !wsl.check_network(install_name)? {
+        status!(
+            "Warning: the network still doesn't work. Check /etc/resolv.conf of the distribution."
+        );
+    }
+
+    Ok(())
+}
+
+/// Ask the name and password of the new user
+fn prompt_user(
+    no_password: bool,
+    min_password_length: Option<usize>,
+) -> Result<(String, Option<String>)> {
+    let user_name: String = dialoguer::Input::new()
+        .with_prompt("Enter new UNIX username")
+        .validate_with(|name: &String| check_user_name(name).map_err(|e| e.to_string()))
+        .interact_text()?;
+
+    let password = if no_password {
+        None
+    } else {
+        Some(prompt_password("New password", min_password_length)?)
+    };
+
+    Ok((user_name, password))
+}
+
+fn prompt_password(prompt: &str, min_password_length: Option<usize>) -> Result<String> {
+    loop {
+        let password = dialoguer::Password::new()
+            .with_prompt(prompt)
+            .with_confirmation("Retype new password", "Passwords do not match.")
+            .interact()?;
+
+        match min_password_length {
+            Some(min) if password.chars().count() < min => {
+                eprintln!("Password must be at least {} characters.", min);
+            }
+            _ => return Ok(password),
+        }
+    }
+}
+
+/// Read the passwords of `users` from lines of `name:password`, as chpasswd takes
+fn read_password_file(
+    path: &Path,
+    users: &[String],
+    min_password_length: Option<usize>,
+) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| anyhow!("Failed to read {}: {}", path.display(), err))?;
+
+    let mut passwords = HashMap::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (name, password) = line.split_once(':').ok_or_else(|| {
+            Failure::Usage(format!(
+                "Invalid line in {}: expected `name:password`",
+                path.display()
+            ))
+        })?;
+        passwords.insert(name.trim().to_string(), password.to_string());
+    }
+
+    for user in users {
+        match passwords.get(user) {
+            None => bail!(Failure::Usage(format!(
+                "No password for {} in {}",
+                user,
+                path.display()
+            ))),
+            Some(password) if password.chars().count() < min_password_length.unwrap_or(0) => {
+                bail!(Failure::Usage(format!(
+                    "Password of {} in {} must be at least {} characters",
+                    user,
+                    path.display(),
+                    min_password_length.unwrap_or(0)
+                )))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(passwords)
+}
+
+fn check_user_home(user_home: &str) -> Result<()> {
+    if !user_home.starts_with('/') {
+        bail!(Failure::Usage(format!(
+            "User home `{}` must be an absolute path",
+            user_home
+        )));
+    }
+    if user_home == "/mnt" || user_home.starts_with("/mnt/") {
+        status!(
+            "Warning: `{}` is on a Windows drive. Unix permissions may not work as expected.",
+            user_home
+        );
+    }
+    Ok(())
+}
+
+/// User names as accepted by useradd by default (e.g. `NAME_REGEX` of Debian's adduser)
+fn check_user_name(name: &str) -> Result<()> {
+    let re = regex::Regex::new(r"^[a-z_][a-z0-9_-]*\$?$")?;
+    if !re.is_match(name) {
+        bail!(Failure::Usage(format!(
+            "Invalid user name `{}`: use lowercase letters, digits, `_` and `-`",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// The data directory is a single directory directly under the data root, which is removed
+/// on rollback, so it must not point to the root itself, its parent or elsewhere
+fn check_data_dir_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    let single = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if !single || name.contains(['/', '\\']) {
+        bail!(Failure::Usage(format!(
+            "Invalid data directory name `{}`: it must be a single directory name",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Rootfs tarballs of common images are a few hundred MB, and some are much larger
+const MIN_TEMP_SPACE: u64 = 1 << 30;
+
+/// Directory for temporary rootfs tarballs, which may be several GB.
+/// `TMPDIR` is honored on Windows as well, where the system temp directory ignores it.
+fn temp_dir_for_rootfs(dir: Option<PathBuf>, force: bool) -> Result<PathBuf> {
+    let dir = dir
+        .or_else(|| std::env::var_os("TMPDIR").map(PathBuf::from))
+        .unwrap_or_else(std::env::temp_dir);
+
+    if !dir.is_dir() {
+        bail!(Failure::NotFound(format!(
+            "Temporary directory `{}` does not exist",
+            dir.display()
+        )));
+    }
+    if tempfile::tempfile_in(&dir).is_err() {
+        bail!("Temporary directory {} is not writable", dir.display());
+    }
+
+    let free = free_space(&dir)?;
+    if free < MIN_TEMP_SPACE {
+        let message = format!(
+            "Only {} is free on the volume of {}",
+            HumanBytes(free),
+            dir.display()
+        );
+        if !force {
+            bail!("{}. Use --temp-dir to choose another directory", message);
+        }
+        progress::emit(InstallEvent::Warning { message: &message });
+    }
+
+    Ok(dir)
+}
+
+/// Restore the state before installation. `created_dir` is removed as well if given.
+fn rollback_install(wsl: &WSL, install_name: &str, created_dir: Option<&Path>) {
+    eprintln!(
+        "Installation failed. Removing {} (use --no-rollback to keep it)...",
+        install_name
+    );
+
+    if let Err(err) = wsl.unregister_distribution(install_name) {
+        eprintln!("Failed to unregister {}: {:#}", install_name, err);
+    }
+
+    if let Some(dir) = created_dir {
+        if let Err(err) = fs::remove_dir_all(extended_length_path(dir)) {
+            eprintln!("Failed to remove {}: {}", dir.display(), err);
+        }
+    }
+
+    let removed = Metadata::load().and_then(|mut metadata| {
+        metadata.distros.remove(install_name);
+        metadata.save()
+    });
+    if let Err(err) = removed {
+        eprintln!("Failed to update metadata: {:#}", err);
+    }
+}
+
+/// Whether creating the users of `install` asks for their names or passwords on the terminal
+fn needs_user_prompt(
+    needs_users: bool,
+    users: &[String],
+    no_password: bool,
+    password_file: Option<&Path>,
+) -> bool {
+    needs_users && (users.is_empty() || (!no_password && password_file.is_none()))
+}
+
+/// Let the user choose a tag of the image, falling back to `latest`
+fn select_tag(distro: &str) -> String {
+    if !io::stdin().is_terminal() {
+        return "latest".to_string();
+    }
+
+    let tags = match registry::list_tags_cached(&Repository::parse(distro)) {
+        Ok(tags) if !tags.is_empty() => tags,
+        Ok(_) => return "latest".to_string(),
+        Err(err) => {
+            status!("Warning: failed to list tags, using latest: {:#}", err);
+            return "latest".to_string();
+        }
+    };
+
+    let default = tags.iter().position(|tag| tag == "latest").unwrap_or(0);
+
+    match dialoguer::Select::new()
+        .with_prompt(format!("Select a tag of {}", distro))
+        .items(&tags)
+        .default(default)
+        .interact()
+    {
+        Ok(i) => tags[i].clone(),
+        Err(_) => "latest".to_string(),
+    }
+}
+
+fn parse_distro_name(distro: &str) -> Result<(String, String)> {
+    let re = regex::Regex::new("^([^:]+)(:([^:]+))?$")?;
+
+    let cap = re
+        .captures(distro)
+        .ok_or_else(|| anyhow!("failed to parse distribution name"))?;
+
+    let distro_name = cap[1].to_string();
+    let distro_tag = match cap.get(3) {
+        Some(tag) => tag.as_str().to_string(),
+        // Some registries use another moving tag, such as `stable` or `lts`
+        None => Config::load()?
+            .default_tag(&distro_name)
+            .unwrap_or("latest")
+            .to_string(),
+    };
+
+    Ok((distro_name, distro_tag))
+}
+
+/// Make child processes (curl, skopeo and the container runtime) use `proxy` except for `no_proxy`,
+/// through the standard environment variables that they honor when set outside as well.
+/// The docker daemon pulls images by itself, so it needs its own proxy settings.
+fn set_proxy(proxy: Option<&str>, no_proxy: Option<&str>) {
+    if let Some(proxy) = proxy {
+        // curl ignores the uppercase HTTP_PROXY
+        for key in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+            std::env::set_var(key, proxy);
+        }
+    }
+    if let Some(no_proxy) = no_proxy {
+        for key in ["NO_PROXY", "no_proxy"] {
+            std::env::set_var(key, no_proxy);
+        }
+    }
+}
+
+/// Add the `\\?\` prefix to an absolute Windows path that may exceed MAX_PATH,
+/// so that it works even if long path support is not enabled.
+fn extended_length_path(path: &Path) -> PathBuf {
+    // MAX_PATH minus the room for a 8.3 file name, which is the limit for directories
+    const MAX_DIR_PATH: usize = 248;
+
+    if path.as_os_str().len() < MAX_DIR_PATH {
+        return path.to_owned();
+    }
+
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => {
+                let mut ret = OsString::from(r"\\?\");
+                ret.push(path.as_os_str());
+                PathBuf::from(ret)
+            }
+            Prefix::UNC(server, share) => {
+                let mut ret = OsString::from(r"\\?\UNC\");
+                ret.push(server);
+                ret.push(r"\");
+                ret.push(share);
+                PathBuf::from(ret).join(components.as_path())
+            }
+            _ => path.to_owned(),
+        },
+        _ => path.to_owned(),
+    }
+}
+
+fn sanitize_path(s: &str) -> String {
+    s.chars().map(|c| if c == '/' { '-' } else { c }).collect()
+}
+
+/// Download tarball of rootfs
+#[argopt::subcmd]
+fn download(
+    /// Show docker's progress output
+    #[opt(long, short)]
+    verbose: bool,
+    ///
+    /// Container runtime to use (docker, podman or nerdctl; tries them in order by default)
+    #[opt(long)]
+    runtime: Option<Runtime>,
+    ///
+    /// Pull images via this registry mirror (e.g. mirror.example.com:5000)
+    #[opt(long)]
+    registry_mirror: Option<String>,
+    ///
+    /// Pull from the original registry if the mirror fails
+    #[opt(long, requires = "registry-mirror")]
+    mirror_fallback: bool,
+    ///
+    /// Pull from Docker Hub first, and use the registry mirror only if it fails
+    #[opt(long, requires = "registry-mirror", conflicts_with = "mirror-fallback")]
+    prefer_docker_hub: bool,
+    ///
+    /// HTTP proxy for pulling images and registry requests (e.g. http://proxy.example.com:8080)
+    #[opt(long)]
+    proxy: Option<String>,
+    ///
+    /// Comma-separated hosts to access without the proxy (e.g. registry.internal,.corp.example.com)
+    #[opt(long)]
+    no_proxy: Option<String>,
+    ///
+    /// Limit the download speed from registries (e.g. 1M for 1 MiB/s); for --output-format oci only
+    #[opt(long)]
+    max_rate: Option<ByteSize>,
+    ///
+    /// Free space to keep on the cache volume, removing old cached rootfs (e.g. 10G)
+    #[opt(long)]
+    min_free_space: Option<ByteSize>,
+    ///
+    /// When to pull the image instead of using the cached rootfs (always, missing or never)
+    #[opt(long, default_value = "missing")]
+    pull_policy: PullPolicy,
+    ///
+    /// Overwrite the output file if it already exists
+    #[opt(long)]
+    overwrite: bool,
+    ///
+    /// Skip tags whose output file already exists, instead of failing
+    #[opt(long, conflicts_with = "overwrite")]
+    skip_existing: bool,
+    ///
+    /// What to save: a flattened rootfs tarball (rootfs) or an OCI image layout directory (oci)
+    #[opt(long, default_value = "rootfs")]
+    output_format: OutputFormat,
+    ///
+    /// Format of the output file (tar, gzip or zstd)
+    #[opt(long, default_value = "gzip")]
+    format: ArchiveFormat,
+    ///
+    /// Compression level (defaults to fast compression)
+    #[opt(long)]
+    level: Option<i32>,
+    ///
+    /// Output file (defaults to <distro>-<tag>.<ext>)
+    #[opt(long, short, parse(from_os_str))]
+    output: Option<PathBuf>,
+    ///
+    /// Directory to save the file with the default name in (created if needed)
+    #[opt(long, parse(from_os_str), conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
+    ///
+    /// Tag to download in addition to the one in the name; repeatable
+    #[opt(long, number_of_values = 1, conflicts_with = "output")]
+    tag: Vec<String>,
+    ///
+    /// Keep only this path in the rootfs (e.g. /usr); repeatable
+    #[opt(long, parse(from_os_str), number_of_values = 1)]
+    include: Vec<PathBuf>,
+    ///
+    /// Remove this path from the rootfs (e.g. /usr/share/doc); repeatable
+    #[opt(long, parse(from_os_str), number_of_values = 1)]
+    exclude: Vec<PathBuf>,
+    ///
+    /// Sort entries and fix timestamps (to $SOURCE_DATE_EPOCH or 0) for byte-identical output
+    #[opt(long)]
+    reproducible: bool,
+    ///
+    /// Remove character and block devices (e.g. in /dev) from the rootfs
+    #[opt(long)]
+    exclude_dev: bool,
+    ///
+    /// Print the result as JSON to stdout (one line per file)
+    #[opt(long)]
+    json: bool,
+    ///
+    /// How to report progress (console, or json for newline-delimited JSON events to stderr)
+    #[opt(long, default_value = "console")]
+    progress: ProgressMode,
+    ///
+    /// Name of distribution to download (e.g. ubuntu, ubuntu:20.04, "ubuntu:{22.04,24.04}")
+    distro: String,
+) -> Result<()> {
+    output::set_json(json);
+    progress::set_mode(progress);
+    set_proxy(proxy.as_deref(), no_proxy.as_deref());
+    registry::set_max_rate(max_rate.map(|rate| rate.0));
+
+    let (distro_name, distro_tags) = parse_distro_name(&distro)?;
+
+    let mut tags = expand_braces(&distro_tags);
+    // The tag in the name defaults to `latest`, which is not wanted when --tag is given
+    if !tag.is_empty() && !distro.contains(':') {
+        tags.clear();
+    }
+    for extra in tag {
+        if !tags.contains(&extra) {
+            tags.push(extra);
+        }
+    }
+
+    let pull_opts = PullOptions::new(
+        verbose,
+        runtime,
+        registry_mirror.as_deref(),
+        mirror_fallback,
+        prefer_docker_hub,
+        pull_policy,
+        min_free_space
+            .or(Config::load()?.min_free_space)
+            .map(|size| size.0),
+    )?;
+
+    if output_format == OutputFormat::Oci {
+        if !include.is_empty() || !exclude.is_empty() || reproducible {
+            bail!(Failure::Usage(
+                "--include, --exclude and --reproducible cannot be used with --output-format oci"
+                    .to_string()
+            ));
+        }
+
+        // All tags go into one layout
+        let dir = output
+            .or(output_dir)
+            .unwrap_or_else(|| PathBuf::from(sanitize_path(&distro_name)));
+        download_oci_layout(
+            &distro_name,
+            &tags,
+            &dir,
+            &pull_opts,
+            overwrite,
+            skip_existing,
+            json,
+        )?;
+
+        if max_rate.is_some() {
+            if let Some((bytes, rate)) = registry::transfer_stats() {
+                status!(
+                    "Downloaded {} at {}/s on average",
+                    HumanBytes(bytes),
+                    HumanBytes(rate)
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    if max_rate.is_some() {
+        progress::emit(InstallEvent::Warning {
+            message: "--max-rate only applies to --output-format oci; container runtimes pull at full speed",
+        });
+    }
+
+    if tags.len() > 1 && output.is_some() {
+        bail!(Failure::Usage(
+            "--output cannot be used with multiple tags. Use --output-dir instead".to_string()
+        ));
+    }
+
+    let targets = tags
+        .into_iter()
+        .map(|tag| {
+            let fname = match &output {
+                Some(output) => output.clone(),
+                None => output_dir.clone().unwrap_or_default().join(format!(
+                    "{}-{}{}",
+                    sanitize_path(&distro_name),
+                    tag,
+                    format.extension()
+                )),
+            };
+            (tag, fname)
+        })
+        .collect::<Vec<_>>();
+
+    // Fail before pulling, which takes a while
+    for (_, fname) in &targets {
+        let dir = fname
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(dir)?;
+        if tempfile::tempfile_in(dir).is_err() {
+            bail!("Directory {} is not writable", dir.display());
+        }
+
+        if fname.exists() && !overwrite && !skip_existing {
+            bail!(Failure::AlreadyExists(format!(
+                "File `{}` already exists. Use --overwrite to replace it",
+                fname.display()
+            )));
+        }
+    }
+
+    let mtime = if reproducible {
+        Some(match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => epoch
+                .parse()
+                .map_err(|_| anyhow!("Invalid SOURCE_DATE_EPOCH: {}", epoch))?,
+            Err(_) => 0,
+        })
+    } else {
+        None
+    };
+    let filter = RootfsFilter {
+        include,
+        exclude,
+        mtime,
+        exclude_dev,
+    };
+
+    let mut failed = vec![];
+
+    for (tag, fname) in &targets {
+        if skip_existing && fname.exists() {
+            status!(
+                "Skipping {}:{}: {} already exists",
+                distro_name,
+                tag,
+                fname.display()
+            );
+            continue;
+        }
+
+        status!("Downloading {}:{}", distro_name, tag);
+
+        let digest = if filter.is_empty() {
+            get_distribution_rootfs(&distro_name, tag, fname, format, level, &pull_opts)
+        } else {
+            get_filtered_rootfs(fname, format, level, &filter, |tar| {
+                get_distribution_rootfs(
+                    &distro_name,
+                    tag,
+                    tar,
+                    ArchiveFormat::Tar,
+                    None,
+                    &pull_opts,
+                )
+            })
+        };
+
+        let digest = match digest {
+            Ok(digest) => digest,
+            Err(err) if targets.len() > 1 => {
+                eprintln!("{}:{}: {:#}", distro_name, tag, err);
+                failed.push(tag.as_str());
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "path": fname.canonicalize()?,
+                    "image": format!("{}:{}", distro_name, tag),
+                    "digest": digest,
+                })
+            );
+        } else {
+            status!("Saved rootfs to {}", fname.display());
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "Downloaded {} of {} tags. Failed on: {}",
+            targets.len() - failed.len(),
+            targets.len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Copy the tags of the image into the OCI image layout `dir`, each as its own reference
+fn download_oci_layout(
+    distro_name: &str,
+    tags: &[String],
+    dir: &Path,
+    pull_opts: &PullOptions,
+    overwrite: bool,
+    skip_existing: bool,
+    json: bool,
+) -> Result<()> {
+    let layout_exists = dir.join("oci-layout").is_file();
+    if !layout_exists && dir.is_dir() && fs::read_dir(dir)?.next().is_some() {
+        bail!(
+            "Directory `{}` is not empty and is not an OCI image layout",
+            dir.display()
+        );
+    }
+
+    // Fail before copying, which takes a while
+    let mut existing = vec![];
+    if layout_exists {
+        let layout = OciLayout::open(dir)?;
+        for tag in tags {
+            if layout.ref_digest(tag)?.is_some() {
+                if !overwrite && !skip_existing {
+                    bail!(Failure::AlreadyExists(format!(
+                        "Tag `{}` already exists in `{}`. Use --overwrite to replace it",
+                        tag,
+                        dir.display()
+                    )));
+                }
+                existing.push(tag);
+            }
+        }
+    }
+
+    let mut failed = vec![];
+
+    for tag in tags {
+        if skip_existing && existing.contains(&tag) {
+            status!(
+                "Skipping {}:{}: already exists in {}",
+                distro_name,
+                tag,
+                dir.display()
+            );
+            continue;
+        }
+
+        status!("Copying {}:{} to {}", distro_name, tag, dir.display());
+
+        let images = pull_opts.images(distro_name, tag);
+        let mut result = Ok(());
+        for (i, image) in images.iter().enumerate() {
+            // skopeo can't limit the speed, so the blobs are downloaded with curl
+            result = if registry::is_rate_limited() {
+                let name = image.strip_suffix(&format!(":{}", tag)).unwrap_or(image);
+                registry::copy_to_layout(&Repository::parse(name), tag, oci::linux_arch(), dir, tag)
+            } else {
+                oci::copy_to_layout(image, dir, tag, pull_opts.verbose)
+            };
+            match &result {
+                Ok(()) => break,
+                // The last error is reported below
+                Err(err) if i + 1 < images.len() => eprintln!("{:#}", err),
+                Err(_) => {}
+            }
+        }
+
+        match result {
+            Ok(()) => {}
+            Err(err) if tags.len() > 1 => {
+                eprintln!("{}:{}: {:#}", distro_name, tag, err);
+                failed.push(tag.as_str());
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+
+        let digest = OciLayout::open(dir)?.ref_digest(tag)?;
+
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "path": dir.canonicalize()?,
+                    "image": format!("{}:{}", distro_name, tag),
+                    "digest": digest.map(|digest| format!("{}@{}", distro_name, digest)),
+                })
+            );
+        } else {
+            status!("Saved {}:{} to {}", distro_name, tag, dir.display());
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "Downloaded {} of {} tags. Failed on: {}",
+            tags.len() - failed.len(),
+            tags.len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// What `download` saves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Flattened rootfs tarball, importable by `wsl.exe --import`
+    Rootfs,
+    /// OCI image layout directory with the layers as they are
+    Oci,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "rootfs" => Self::Rootfs,
+            "oci" => Self::Oci,
+            _ => bail!("Unknown output format: {} (expected rootfs or oci)", s),
+        })
+    }
+}
+
+/// Save the rootfs to `path`, rewritten by `archive::filter_rootfs`.
+/// `pull` saves the whole rootfs as an uncompressed tarball to the given path.
+fn get_filtered_rootfs(
+    path: &Path,
+    format: ArchiveFormat,
+    level: Option<i32>,
+    filter: &RootfsFilter,
+    pull: impl FnOnce(&Path) -> Result<Option<String>>,
+) -> Result<Option<String>> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    let tar = NamedTempFile::new_in(dir)?.into_temp_path();
+    let _temp_guard = cleanup::remove_on_interrupt(&tar);
+
+    let digest = pull(&tar)?;
+
+    status!("Rewriting rootfs...");
+
+    let mut temp_file = NamedTempFile::new_in(dir)?;
+    let _output_guard = cleanup::remove_on_interrupt(temp_file.path());
+    archive::filter_rootfs(
+        io::BufReader::new(fs::File::open(&tar)?),
+        BufWriter::new(&mut temp_file),
+        format,
+        level,
+        filter,
+    )?;
+    temp_file.persist(path)?;
+
+    Ok(digest)
+}
+
+/// Expand `{a,b}` in a tag like the shell does (e.g. `{20,22}.04` -> `20.04`, `22.04`)
+fn expand_braces(s: &str) -> Vec<String> {
+    let (start, end) = match (s.find('{'), s.find('}')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => return vec![s.to_string()],
+    };
+
+    let (prefix, suffix) = (&s[..start], &s[end + 1..]);
+
+    s[start + 1..end]
+        .split(',')
+        .flat_map(|item| expand_braces(&format!("{}{}{}", prefix, item, suffix)))
+        .collect()
+}
+
+/// Build a customized rootfs tarball from a template
+#[argopt::subcmd]
+fn build(
+    /// Show docker's progress output
+    #[opt(long, short)]
+    verbose: bool,
+    ///
+    /// Container runtime to use (docker, podman or nerdctl; tries them in order by default)
+    #[opt(long)]
+    runtime: Option<Runtime>,
+    ///
+    /// Output file (the format is decided by the extension; defaults to <template>.tar.gz)
+    #[opt(long, short, parse(from_os_str))]
+    output: Option<PathBuf>,
+    ///
+    /// Overwrite the output file if it already exists
+    #[opt(long)]
+    overwrite: bool,
+    ///
+    /// Directory for the temporary distribution and tarballs (defaults to $TMPDIR or the system temp directory)
+    #[opt(long, parse(from_os_str))]
+    temp_dir: Option<PathBuf>,
+    ///
+    /// Template file (TOML)
+    #[opt(parse(from_os_str))]
+    template: PathBuf,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    let tmpl = Template::load(&template)?;
+
+    let output = output.unwrap_or_else(|| {
+        let stem = template.file_stem().unwrap_or_default().to_string_lossy();
+        PathBuf::from(format!("{}{}", stem, ArchiveFormat::Gzip.extension()))
+    });
+    let format = ArchiveFormat::from_file_name(&output).ok_or_else(|| {
+        anyhow!(
+            "Unknown format of output file: {} (expected .tar, .tar.gz or .tar.zst)",
+            output.display()
+        )
+    })?;
+
+    if output.exists() && !overwrite {
+        bail!(Failure::AlreadyExists(format!(
+            "File `{}` already exists. Use --overwrite to replace it",
+            output.display()
+        )));
+    }
+
+    let temp_dir = temp_dir_for_rootfs(temp_dir, false)?;
+
+    let (distro_name, distro_tag) = parse_distro_name(&tmpl.base)?;
+    let pull_opts = PullOptions::new(
+        verbose,
+        runtime,
+        None,
+        false,
+        false,
+        PullPolicy::Missing,
+        Config::load()?.min_free_space.map(|size| size.0),
+    )?;
+
+    let import_archive = ImportFormat::fastest(&wsl.import_formats()).archive_format();
+    let base_rootfs = tempfile::Builder::new()
+        .suffix(import_archive.extension())
+        .tempfile_in(&temp_dir)?
+        .into_temp_path();
+    get_distribution_rootfs(
+        &distro_name,
+        &distro_tag,
+        &base_rootfs,
+        import_archive,
+        None,
+        &pull_opts,
+    )?;
+
+    // Provision in a temporary distribution, removed afterward
+    let build_name = format!("wsl-get-build-{}", std::process::id());
+    let build_dir = tempfile::tempdir_in(&temp_dir)?;
+
+    status!("Registering temporary distribution {}...", build_name);
+    wsl.register_distribution(&build_name, build_dir.path(), &base_rootfs, 2, None, 1)?;
+
+    defer! {
+        if let Err(err) = wsl.unregister_distribution(&build_name) {
+            eprintln!("Failed to unregister {}: {:#}", build_name, err);
+        }
+    }
+
+    if !tmpl.packages.is_empty() {
+        status!("Installing packages...");
+        let command =
+            template::install_command(&tmpl.packages, |file| wsl.file_exists(&build_name, file))?
+                .ok_or_else(|| anyhow!("No supported package manager found in {}", tmpl.base))?;
+
+        if wsl.launch_interactive(&build_name, &command, false)? != 0 {
+            bail!("Failed to install packages");
+        }
+    }
+
+    for step in &tmpl.run {
+        status!("Running: {}", step);
+        let ec = wsl.launch_interactive(&build_name, step, false)?;
+        if ec != 0 {
+            bail!("Command exited with code {}: {}", ec, step);
+        }
+    }
+
+    wsl.terminate_distribution(&build_name)?;
+
+    status!("Exporting rootfs...");
+    let tar = tempfile::Builder::new()
+        .suffix(ArchiveFormat::Tar.extension())
+        .tempfile_in(&temp_dir)?
+        .into_temp_path();
+    wsl.export_distribution(&build_name, &tar)?;
+
+    let mut temp_file = NamedTempFile::new_in(
+        output
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new(".")),
+    )?;
+    format.compress(&mut fs::File::open(&tar)?, &mut temp_file, None)?;
+    temp_file.persist(&output)?;
+
+    status!("Saved rootfs to {}", output.display());
+
+    Ok(())
+}
+
+/// Remove data directories of uninstalled distributions
+#[argopt::subcmd]
+fn gc(
+    /// Remove the cached rootfs tarballs as well
+    #[opt(long)]
+    cache: bool,
+    ///
+    /// Answer yes for all questions
+    #[opt(long, short)]
+    yes: bool,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    let root = data_root()?;
+    let installed = wsl.list_installed_distros()?;
+    let mut metadata = Metadata::load()?;
+
+    // Where WSL actually keeps each distribution, which may differ from our metadata
+    // (e.g. moved with `wsl.exe --manage --move`), and where our metadata says.
+    // Paths are canonicalized since they may be spelled differently.
+    let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    let base_paths = wsl.base_paths()?;
+    let mut in_use = vec![];
+    for distro in &installed {
+        let mut paths = vec![];
+        paths.extend(base_paths.get(distro).cloned());
+        paths.extend(metadata.distros.get(distro).map(|m| m.data_dir.clone()));
+        if paths.is_empty() {
+            paths.push(root.join(distro));
+        }
+        in_use.extend(paths.iter().map(|path| canonical(path)));
+    }
+
+    if !root.exists() {
+        println!("Nothing to clean up");
+        return Ok(());
+    }
+
+    let cache_dir = cache_root()?;
+
+    let mut orphans = vec![];
+    for entry in fs::read_dir(&root)? {
+        let path = entry?.path();
+        if path.is_dir() && !in_use.contains(&canonical(&path)) && path != cache_dir {
+            let size = dir_size(&path)?;
+            orphans.push((path, size));
+        }
+    }
+
+    let rootfs_cache = cache_dir.join("rootfs");
+    if cache && rootfs_cache.is_dir() {
+        let size = dir_size(&rootfs_cache)?;
+        orphans.push((rootfs_cache, size));
+    }
+
+    if orphans.is_empty() {
+        println!("Nothing to clean up");
+        return Ok(());
+    }
+
+    for (path, size) in &orphans {
+        println!("{} ({})", path.display(), HumanBytes(*size));
+    }
+
+    if !yes
+        && !dialoguer::Confirm::new()
+            .with_prompt("Do you really want to delete these directories?")
+            .interact()?
+    {
+        return Ok(());
+    }
+
+    let mut reclaimed = 0;
+    for (path, size) in &orphans {
+        fs::remove_dir_all(path)?;
+        reclaimed += size;
+    }
+
+    metadata
+        .distros
+        .retain(|distro, _| installed.contains(distro));
+    metadata.save()?;
+
+    println!("Reclaimed {}", HumanBytes(reclaimed));
+
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut ret = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        ret += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(ret)
+}
+
+/// List available tags of distribution
+#[argopt::subcmd]
+fn tags(
+    /// Show only tags containing this string
+    #[opt(long, short)]
+    filter: Option<String>,
+    ///
+    /// Show only tags with an image for this architecture (e.g. amd64, arm64)
+    #[opt(long)]
+    arch: Option<String>,
+    ///
+    /// Show only tags with this annotation (KEY=VALUE, or KEY to check existence); repeatable
+    #[opt(long, number_of_values = 1)]
+    annotation: Vec<String>,
+    ///
+    /// HTTP proxy for pulling images and registry requests (e.g. http://proxy.example.com:8080)
+    #[opt(long)]
+    proxy: Option<String>,
+    ///
+    /// Comma-separated hosts to access without the proxy (e.g. registry.internal,.corp.example.com)
+    #[opt(long)]
+    no_proxy: Option<String>,
+    ///
+    /// Limit the download speed from the registry (e.g. 1M for 1 MiB/s)
+    #[opt(long)]
+    max_rate: Option<ByteSize>,
+    ///
+    /// Name of distribution (e.g. ubuntu, ghcr.io/owner/image)
+    distro: String,
+) -> Result<()> {
+    set_proxy(proxy.as_deref(), no_proxy.as_deref());
+    registry::set_max_rate(max_rate.map(|rate| rate.0));
+    defer! {
+        if max_rate.is_some() {
+            if let Some((bytes, rate)) = registry::transfer_stats() {
+                status!("Downloaded {} at {}/s on average", HumanBytes(bytes), HumanBytes(rate));
+            }
+        }
+    }
+
+    let (distro_name, _) = parse_distro_name(&distro)?;
+    let repo = Repository::parse(&distro_name);
+
+    let tags = registry::list_tags(&repo)?
+        .into_iter()
+        .filter(|tag| filter.as_ref().is_none_or(|filter| tag.contains(filter)))
+        .collect::<Vec<_>>();
+
+    if arch.is_none() && annotation.is_empty() {
+        for tag in tags {
+            println!("{}", tag);
+        }
+        return Ok(());
+    }
+
+    // Filtering by manifests needs a request per tag
+    let infos = registry::inspect_tags(&repo, &tags)?;
+
+    for tag in &tags {
+        let info = match infos.get(tag) {
+            Some(info) => info,
+            None => continue,
+        };
+
+        let arch_matches = arch.as_ref().is_none_or(|arch| info.has_arch(arch));
+        let annotations_match =
+            annotation
+                .iter()
+                .all(|annotation| match annotation.split_once('=') {
+                    Some((key, value)) => {
+                        info.annotations.get(key).map(String::as_str) == Some(value)
+                    }
+                    None => info.annotations.contains_key(annotation),
+                });
+
+        if arch_matches && annotations_match {
+            println!("{}", tag);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run command in distribution
+#[argopt::subcmd]
+fn run(
+    /// Run command as root regardless of the default user
+    #[opt(long)]
+    as_root: bool,
+    ///
+    /// Set environment variable (KEY=VALUE) for the command; repeatable
+    #[opt(long, number_of_values = 1)]
+    env: Vec<String>,
+    ///
+    /// Pass environment variable KEY of Windows to the command; repeatable
+    #[opt(long, number_of_values = 1)]
+    env_from_host: Vec<String>,
+    ///
+    /// Name of distribution
+    distro: String,
+    ///
+    /// Command to run (use `--` before options of the command)
+    #[opt(required = true)]
+    command: Vec<String>,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
+
+    let mut vars = vec![];
+    for var in &env {
+        let (key, value) = var.split_once('=').ok_or_else(|| {
+            Failure::Usage(format!("Environment variable must be KEY=VALUE: {}", var))
+        })?;
+        vars.push((check_env_name(key)?.to_string(), value.to_string()));
+    }
+    for key in &env_from_host {
+        let value = std::env::var(check_env_name(key)?)
+            .map_err(|_| anyhow!("Environment variable {} is not set", key))?;
+        vars.push((key.clone(), value));
+    }
+
+    let user = if as_root { Some("root") } else { None };
+    let code = wsl.run(&distro, user, &vars, &command)?;
+
+    if code != 0 {
+        eprintln!("Command exited with code {}", code);
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
+
+/// WSL rejects names with other characters (e.g. spaces) with an obscure error on import.
+/// Note that names are always passed to `wsl.exe` as single arguments.
+fn check_install_name(name: &str) -> Result<()> {
+    let re = regex::Regex::new("^[A-Za-z0-9._-]+( +[A-Za-z0-9._-]+)*$")?;
+    if !re.is_match(name) {
+        bail!(Failure::Usage(format!(
+            "Invalid installing name `{}`: only letters, digits, '.', '_', '-' and inner spaces are allowed",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Hostnames are dot-separated labels of letters, digits and '-' (RFC 1123)
+fn check_hostname(name: &str) -> Result<()> {
+    let re = regex::Regex::new(
+        "^[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?(\\.[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)*$",
+    )?;
+    if name.len() > 253 || !re.is_match(name) {
+        bail!(Failure::Usage(format!("Invalid hostname: {}", name)));
+    }
+    Ok(())
+}
+
+fn check_env_name(name: &str) -> Result<&str> {
+    let re = regex::Regex::new("^[A-Za-z_][A-Za-z0-9_]*$")?;
+    if !re.is_match(name) {
+        bail!(Failure::Usage(format!(
+            "Invalid environment variable name: {}",
+            name
+        )));
+    }
+    Ok(name)
+}
+
+/// Open interactive shell in distribution
+#[argopt::subcmd]
+fn shell(
+    /// User to log in as (defaults to the default user of distribution)
+    #[opt(long, short)]
+    user: Option<String>,
+    ///
+    /// Name of distribution
+    distro: String,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
+
+    if let Some(user) = &user {
+        if wsl.query_uid(&distro, user).is_err() {
+            bail!(Failure::NotFound(format!(
+                "User {} does not exist in {}",
+                user, distro
+            )));
+        }
+    }
+
+    let code = wsl.shell(&distro, user.as_deref())?;
+
+    if code != 0 {
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
+
+/// Run command in every installed distribution
+#[argopt::subcmd(name = "exec-all")]
+fn exec_all(
+    /// Abort on the first failure
+    #[opt(long)]
+    stop_on_error: bool,
+    ///
+    /// Skip this distribution (can be specified multiple times)
+    #[opt(long, number_of_values = 1)]
+    exclude: Vec<String>,
+    ///
+    /// Command to run (use `--` before options of the command)
+    #[opt(required = true)]
+    command: Vec<String>,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    let mut failed = vec![];
+
+    for distro in wsl.list_installed_distros()? {
+        if exclude.contains(&distro) {
+            continue;
+        }
+
+        println!("==> {}", distro);
+
+        // Like `run`, the arguments are passed as they are instead of being joined
+        match wsl.run(&distro, None, &[], &command) {
+            Ok(0) => continue,
+            Ok(code) => eprintln!("{}: exited with code {}", distro, code),
+            Err(err) => eprintln!("{}: {:#}", distro, err),
+        }
+
+        failed.push(distro);
+
+        if stop_on_error {
+            break;
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("Command failed on: {}", failed.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Open distribution in Windows Terminal
+#[argopt::subcmd]
+fn open(distro: String) -> Result<()> {
+    let wsl = WSL::new();
+
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
+
+    match Command::new("wt.exe").arg("-p").arg(&distro).spawn() {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            // Windows Terminal is not installed
+            Command::new("wsl.exe").arg("-d").arg(&distro).status()?;
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
+/// Create a user in an installed distribution and make it the default user
+#[argopt::subcmd(name = "create-user")]
+fn create_user(
+    /// Home directory of the new user (defaults to /home/<user>)
+    #[opt(long)]
+    user_home: Option<String>,
+    ///
+    /// Full name of the new user (stored as the GECOS comment)
+    #[opt(long)]
+    full_name: Option<String>,
+    ///
+    /// Create the user without password (no password prompt)
+    #[opt(long)]
+    no_password: bool,
+    ///
+    /// Require the password to be at least this many characters
+    #[opt(long)]
+    min_password_length: Option<usize>,
+    ///
+    /// Allow the user to use sudo without password
+    #[opt(long)]
+    nopasswd_sudo: bool,
+    ///
+    /// Do not add the user to the wheel or sudo group
+    #[opt(long)]
+    no_group_add: bool,
+    ///
+    /// Extra argument to useradd (e.g. --useradd-arg=--comment=Developer); repeatable
+    #[opt(long, allow_hyphen_values = true, number_of_values = 1)]
+    useradd_arg: Vec<String>,
+    ///
+    /// Name of distribution
+    distro: String,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
+
+    if let Some(user_home) = &user_home {
+        check_user_home(user_home)?;
+    }
+
+    if !io::stdin().is_terminal() {
+        bail!("Cannot ask for the new user since stdin is not a terminal");
+    }
+
+    let (user_name, password) = prompt_user(no_password, min_password_length)?;
+
+    let user = NewUser {
+        name: user_name,
+        password,
+        root_password: true,
+        home: user_home,
+        full_name,
+        nopasswd_sudo,
+        no_group_add,
+        useradd_args: useradd_arg,
+    };
+
+    wsl.create_user(&distro, &user)?;
+
+    let uid = wsl.query_uid(&distro, &user.name)?;
+    wsl.set_default_uid(&distro, uid as _)?;
+
+    // Distributions imported without wsl-get are not recorded
+    Metadata::update(&distro, |m| m.user = Some(user.name.clone()))?;
+
+    // Restart the distribution so that changes in wsl.conf take effect
+    wsl.terminate_distribution(&distro)?;
+
+    status!("Created user {} in {}", user.name, distro);
+
+    Ok(())
+}
+
+/// Set default user of distribution
+#[argopt::subcmd(name = "set-default-user")]
+fn set_default_user(distro: String, user_name: String) -> Result<()> {
+    let wsl = WSL::new();
+
+    let uid = wsl.query_uid(&distro, &user_name)?;
+    wsl.set_default_uid(&distro, uid as _)?;
+
+    Ok(())
+}
+
+/// Show or change configuration of distribution
+#[argopt::subcmd]
+fn config(
+    /// Enable or disable launching Windows programs (true or false)
+    #[opt(long)]
+    interop: Option<bool>,
+    ///
+    /// Enable or disable appending Windows PATH to $PATH (true or false)
+    #[opt(long)]
+    append_path: Option<bool>,
+    ///
+    /// Enable or disable mounting Windows drives (true or false)
+    #[opt(long)]
+    drive_mounting: Option<bool>,
+    ///
+    /// Set the flags to this value as is, in decimal or hexadecimal (e.g. 0x7)
+    #[opt(
+        long,
+        conflicts_with = "interop",
+        conflicts_with = "append-path",
+        conflicts_with = "drive-mounting"
+    )]
+    raw_flags: Option<String>,
+    ///
+    /// Name of distribution
+    distro: String,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    let raw_flags = raw_flags.as_deref().map(parse_flags).transpose()?;
+
+    let conf = wsl.get_distribution_configuration(&distro)?;
+
+    let changes = [
+        (WSL_DISTRIBUTION_FLAGS_ENABLE_INTEROP, interop),
+        (WSL_DISTRIBUTION_FLAGS_APPEND_NT_PATH, append_path),
+        (WSL_DISTRIBUTION_FLAGS_ENABLE_DRIVE_MOUNTING, drive_mounting),
+    ];
+
+    if raw_flags.is_some() || changes.iter().any(|(_, value)| value.is_some()) {
+        let old_flags = conf.wsl_distribution_flags;
+        let mut flags = raw_flags.map_or(old_flags, WSL_DISTRIBUTION_FLAGS);
+        for (flag, value) in changes {
+            match value {
+                Some(true) => flags |= flag,
+                Some(false) => flags = WSL_DISTRIBUTION_FLAGS(flags.0 & !flag.0),
+                None => {}
+            }
+        }
+
+        wsl.configure_distribution(&distro, conf.default_uid, flags)?;
+
+        println!("Before:");
+        print_flags(old_flags);
+        println!("After:");
+        print_flags(flags);
+
+        return Ok(());
+    }
+
+    // Names of distributions are case-insensitive
+    let running = wsl
+        .list_running_distros()?
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(&distro));
+
+    println!("State: {}", if running { "Running" } else { "Stopped" });
+    println!("WSL version: {}", conf.distribution_version);
+    println!("Default UID: {}", conf.default_uid);
+    print_flags(conf.wsl_distribution_flags);
+    println!("Default environment variables:");
+    for env in &conf.default_environment_variables {
+        println!("  {}", env);
+    }
+
+    Ok(())
+}
+
+/// Parse flags in decimal or hexadecimal with `0x`
+fn parse_flags(s: &str) -> Result<u32> {
+    let flags = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    flags.map_err(|_| anyhow!(Failure::Usage(format!("Invalid flags: {}", s))))
+}
+
+fn print_flags(flags: WSL_DISTRIBUTION_FLAGS) {
+    let has_flag = |flag: WSL_DISTRIBUTION_FLAGS| (flags & flag).0 != 0;
+
+    println!("Flags: {:#x}", flags.0);
+    println!(
+        "  Interop: {}",
+        has_flag(WSL_DISTRIBUTION_FLAGS_ENABLE_INTEROP)
+    );
+    println!(
+        "  Append Windows PATH: {}",
+        has_flag(WSL_DISTRIBUTION_FLAGS_APPEND_NT_PATH)
+    );
+    println!(
+        "  Drive mounting: {}",
+        has_flag(WSL_DISTRIBUTION_FLAGS_ENABLE_DRIVE_MOUNTING)
+    );
+}
+
+/// Compare installed packages of two distributions
+#[argopt::subcmd]
+fn diff(
+    /// Name of distribution
+    a: String,
+    ///
+    /// Name of distribution to compare with
+    b: String,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    for distro in [&a, &b] {
+        if !wsl.is_distribution_registered(distro) {
+            bail!(Failure::NotFound(format!(
+                "Distribution {} is not installed",
+                distro
+            )));
+        }
+    }
+
+    let packages_a = packages::list_packages(&wsl, &a)?;
+    let packages_b = packages::list_packages(&wsl, &b)?;
+
+    let version = |version: &Option<String>| version.clone().unwrap_or_default();
+
+    println!("Only in {}:", a);
+    for (name, ver) in &packages_a {
+        if !packages_b.contains_key(name) {
+            println!("  - {} {}", name, version(ver));
+        }
+    }
+
+    println!("Only in {}:", b);
+    for (name, ver) in &packages_b {
+        if !packages_a.contains_key(name) {
+            println!("  + {} {}", name, version(ver));
+        }
+    }
+
+    println!("Different versions:");
+    for (name, ver_a) in &packages_a {
+        match packages_b.get(name) {
+            Some(ver_b) if ver_b != ver_a => {
+                println!("  ~ {} {} -> {}", name, version(ver_a), version(ver_b))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Move distribution to another directory
+#[argopt::subcmd(name = "move")]
+fn move_distro(
+    /// Name of distribution to move
+    distro: String,
+    ///
+    /// Directory to move the VHD of distribution into
+    new_dir: PathBuf,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
+
+    let old_dir = match Metadata::load()?.distros.get(&distro) {
+        Some(meta) => meta.data_dir.clone(),
+        None => wsl
+            .base_path(&distro)?
+            .ok_or_else(|| anyhow!("Failed to find the directory of {}", distro))?,
+    };
+    let old_vhdx = old_dir.join("ext4.vhdx");
+    if !old_vhdx.is_file() {
+        bail!("VHD of {} is not found in {}", distro, old_dir.display());
+    }
+
+    let new_dir = std::env::current_dir()?.join(new_dir);
+    if new_dir == old_dir {
+        bail!("{} is already in {}", distro, new_dir.display());
+    }
+
+    let new_vhdx = new_dir.join("ext4.vhdx");
+    if new_vhdx.exists() {
+        bail!(Failure::AlreadyExists(format!(
+            "{} already exists",
+            new_vhdx.display()
+        )));
+    }
+
+    fs::create_dir_all(extended_length_path(&new_dir))?;
+
+    if tempfile::tempfile_in(&new_dir).is_err() {
+        bail!("Directory {} is not writable", new_dir.display());
+    }
+
+    let file_system = file_system_name(&new_dir)?;
+    if file_system != "NTFS" {
+        bail!(
+            "{} is on a {} volume, but WSL2 requires an NTFS volume",
+            new_dir.display(),
+            file_system
+        );
+    }
+
+    let conf = wsl.get_distribution_configuration(&distro)?;
+
+    wsl.terminate_distribution(&distro)?;
+
+    println!(
+        "Copying {} to {}...",
+        old_vhdx.display(),
+        new_vhdx.display()
+    );
+    fs::copy(
+        extended_length_path(&old_vhdx),
+        extended_length_path(&new_vhdx),
+    )?;
+
+    wsl.unregister_distribution(&distro)?;
+
+    if let Err(err) = wsl.import_in_place(&distro, &new_vhdx) {
+        bail!(
+            "{}\nThe VHD of {} is left in {}; you can register it with `wsl.exe --import-in-place`",
+            err,
+            distro,
+            new_vhdx.display()
+        );
     }
 
+    wsl.configure_distribution(&distro, conf.default_uid, conf.wsl_distribution_flags)?;
+
+    Metadata::update(&distro, |meta| meta.data_dir = new_dir.clone())?;
+
+    // Unregistering removes the VHD, but leaves the directory
+    let _ = fs::remove_dir(&old_dir);
+
+    println!("Complete!");
+
     Ok(())
 }
 
-/// Install distribution
+/// Show where the files of distribution are stored
 #[argopt::subcmd]
-fn install(
-    /// Do not add user after installation
+fn which(
+    /// Print the result as JSON to stdout
     #[opt(long)]
-    no_user: bool,
+    json: bool,
     ///
-    /// Name of distribution to install (e.g. ubuntu, ubuntu:20.04)
+    /// Name of distribution
     distro: String,
-    ///
-    /// Installing name
-    install_name: Option<String>,
 ) -> Result<()> {
     let wsl = WSL::new();
 
-    let base_dirs = BaseDirs::new().unwrap();
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
 
-    let (distro_name, distro_tag) = parse_distro_name(&distro)?;
+    let base_path = wsl
+        .base_path(&distro)?
+        .ok_or_else(|| anyhow!("Failed to find the directory of {}", distro))?;
 
-    let install_name =
-        install_name.unwrap_or_else(|| format!("{}-{}", sanitize_path(&distro_name), distro_tag));
+    // WSL1 keeps the files in a directory instead of a VHD
+    let vhdx = Some(base_path.join("ext4.vhdx")).filter(|vhdx| vhdx.is_file());
 
-    if wsl.is_distribution_registered(&install_name) {
-        bail!("Distribution `{}` is already registered", install_name);
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "base_path": base_path,
+                "vhdx": vhdx,
+            })
+        );
+    } else {
+        println!("Base path: {}", base_path.display());
+        let rootfs = base_path.join("rootfs");
+        match &vhdx {
+            Some(vhdx) => println!("VHD: {}", vhdx.display()),
+            None if rootfs.is_dir() => println!("Root file system: {}", rootfs.display()),
+            None => println!("VHD: not found"),
+        }
     }
 
-    println!("Installing {} as {}", distro, install_name);
+    Ok(())
+}
 
-    println!("Downloading rootfs image...",);
+/// Show how distribution was installed and its current configuration
+#[argopt::subcmd]
+fn info(
+    /// Print everything known about how the distribution was created as JSON (for audits or reproducing it)
+    #[opt(long)]
+    provenance: bool,
+    ///
+    /// Name of distribution
+    distro: String,
+) -> Result<()> {
+    let wsl = WSL::new();
 
-    let tar_gz = NamedTempFile::new()?;
-    let tar_gz_path = tar_gz.into_temp_path();
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
 
-    get_distribution_rootfs_tar_gz(&distro_name, &distro_tag, &tar_gz_path)?;
+    let metadata = Metadata::load()?;
+    let meta = metadata.distros.get(&distro);
+    let entries = history::load()?;
+    let install = history::last_install(&entries, &distro);
+    let conf = wsl.get_distribution_configuration(&distro)?;
+    // Not launched only to look up the name of the user
+    let default_user = default_user_name(&wsl, &metadata, &distro, conf.default_uid, false)?;
 
-    println!("Registering distribution...",);
+    if provenance {
+        let has_flag = |flag: WSL_DISTRIBUTION_FLAGS| (conf.wsl_distribution_flags & flag).0 != 0;
 
-    let register_distro = || -> Result<()> {
-        let distro_dir = base_dirs.cache_dir().join("wsl-get").join(&install_name);
-        fs::create_dir_all(&distro_dir)?;
-        wsl.register_distribution(&install_name, &distro_dir, &tar_gz_path)?;
-        Ok(())
-    };
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "name": distro,
+                "installed_by_wsl_get": meta.is_some(),
+                "image": meta.and_then(|m| m.image.as_ref()),
+                "digest": meta.and_then(|m| m.digest.as_ref()),
+                "installed_at": install.map(|entry| entry.timestamp),
+                "install_args": install.map(|entry| &entry.args),
+                "data_dir": meta.map(|m| &m.data_dir),
+                "stage": meta.map(|m| m.stage),
+                "user": meta.and_then(|m| m.user.as_ref()),
+                "config": {
+                    "version": conf.distribution_version,
+                    "default_uid": conf.default_uid,
+                    "default_user": default_user,
+                    "flags": conf.wsl_distribution_flags.0,
+                    "interop": has_flag(WSL_DISTRIBUTION_FLAGS_ENABLE_INTEROP),
+                    "append_path": has_flag(WSL_DISTRIBUTION_FLAGS_APPEND_NT_PATH),
+                    "drive_mounting": has_flag(WSL_DISTRIBUTION_FLAGS_ENABLE_DRIVE_MOUNTING),
+                    "environment": conf.default_environment_variables,
+                },
+                "history": entries
+                    .iter()
+                    .filter(|entry| entry.distro == distro)
+                    .collect::<Vec<_>>(),
+            }))?
+        );
+        return Ok(());
+    }
 
-    if no_user {
-        register_distro()?;
-    } else {
-        let user_name: String = dialoguer::Input::new()
-            .with_prompt("Enter new UNIX username")
-            .interact_text()?;
+    println!("Name: {}", distro);
+    match meta {
+        Some(meta) => {
+            println!("Image: {}", meta.image.as_deref().unwrap_or("-"));
+            println!("Digest: {}", meta.digest.as_deref().unwrap_or("-"));
+            println!("Data directory: {}", meta.data_dir.display());
+        }
+        None => println!("Image: - (not installed by wsl-get)"),
+    }
+    if let Some(install) = install {
+        println!(
+            "Installed: {}",
+            install
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+        if !install.args.is_empty() {
+            println!("Command: wsl-get {}", install.args.join(" "));
+        }
+    }
+    println!("Version: {}", conf.distribution_version);
+    println!("Default user: {}", default_user);
+    print_flags(conf.wsl_distribution_flags);
 
-        let password = dialoguer::Password::new()
-            .with_prompt("New password")
-            .with_confirmation("Retype new password", "Passwords do not match.")
-            .interact()?;
+    Ok(())
+}
 
-        register_distro()?;
+/// Try to recover a distribution that fails to launch, without removing its data
+#[argopt::subcmd]
+fn repair(
+    /// Also reset the flags (interop, Windows PATH and drive mounting) to the defaults
+    #[opt(long)]
+    reset_flags: bool,
+    ///
+    /// Name of distribution to repair
+    distro: String,
+) -> Result<()> {
+    let wsl = WSL::new();
 
-        wsl.create_user(&install_name, &user_name, &password)?;
-        let uid = wsl.query_uid(&install_name, &user_name)?;
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
 
-        let conf = wsl.get_distribution_configuration(&install_name)?;
-        wsl.configure_distribution(&install_name, uid as _, conf.wsl_distribution_flags)?;
+    let mut problems = 0;
+
+    println!("Terminating {}...", distro);
+    if let Err(err) = wsl.terminate_distribution(&distro) {
+        println!("  Problem: {:#}", err);
+        problems += 1;
     }
 
-    println!("Complete!");
+    let conf = wsl.get_distribution_configuration(&distro)?;
 
-    Ok(())
-}
+    let meta = Metadata::load()?.distros.remove(&distro);
+    let dir = match &meta {
+        Some(meta) => Some(meta.data_dir.clone()),
+        None => wsl.base_path(&distro)?,
+    };
 
-fn get_distribution_rootfs_tar_gz(distro: &str, tag: &str, path: &Path) -> Result<()> {
-    println!("Pulling image...");
+    println!("Checking the files of {}...", distro);
+    match &dir {
+        // WSL1 keeps the files in a directory instead of a VHD
+        Some(dir) if conf.distribution_version == 1 => {
+            if !dir.join("rootfs").is_dir() {
+                println!("  Problem: rootfs is not found in {}", dir.display());
+                problems += 1;
+            }
+        }
+        Some(dir) => {
+            let vhdx = dir.join("ext4.vhdx");
+            match fs::metadata(extended_length_path(&vhdx)) {
+                Ok(meta) if meta.len() > 0 => {}
+                Ok(_) => {
+                    println!("  Problem: {} is empty", vhdx.display());
+                    problems += 1;
+                }
+                Err(err) => {
+                    println!("  Problem: cannot read {}: {}", vhdx.display(), err);
+                    problems += 1;
+                }
+            }
+        }
+        None => {
+            println!("  Problem: failed to find the directory of {}", distro);
+            problems += 1;
+        }
+    }
 
-    let stat = Command::new("docker")
-        .arg("pull")
-        .arg(format!("{}:{}", distro, tag))
-        .status()?;
+    println!("Starting {}...", distro);
+    match wsl.run_capture(&distro, "/bin/true") {
+        Ok(output) if output.exit_code == 0 => {}
+        Ok(output) => {
+            println!(
+                "  Problem: failed to start (exit code {})\n{}",
+                output.exit_code,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+            problems += 1;
+        }
+        Err(err) => {
+            println!("  Problem: failed to start: {:#}", err);
+            problems += 1;
+        }
+    }
 
-    if !stat.success() {
-        bail!("Failed to pull distribution: {}:{}", distro, tag);
+    // Fall back to root if the default user has been removed
+    println!("Checking the default user...");
+    let mut default_uid = conf.default_uid;
+    if let Some(user) = meta.as_ref().and_then(|meta| meta.user.as_ref()) {
+        match wsl.query_uid(&distro, user) {
+            Ok(uid) if uid as u32 != default_uid => {
+                println!("  Fixed: default user is reset to {}", user);
+                default_uid = uid as _;
+            }
+            Ok(_) => {}
+            Err(_) => println!(
+                "  Warning: user {} created on installation is not found",
+                user
+            ),
+        }
+    }
+    if default_uid != 0 {
+        let exists = wsl
+            .run_capture(&distro, &format!("/usr/bin/getent passwd {}", default_uid))
+            .is_ok_and(|output| output.exit_code == 0);
+        if !exists {
+            println!(
+                "  Fixed: UID {} does not exist; default user is reset to root",
+                default_uid
+            );
+            default_uid = 0;
+        }
     }
 
-    println!("Exporting rootfs...");
+    let flags = if reset_flags {
+        WSL_DISTRIBUTION_FLAGS_ENABLE_INTEROP
+            | WSL_DISTRIBUTION_FLAGS_APPEND_NT_PATH
+            | WSL_DISTRIBUTION_FLAGS_ENABLE_DRIVE_MOUNTING
+    } else {
+        conf.wsl_distribution_flags
+    };
 
-    let output = Command::new("docker")
-        .arg("create")
-        .arg(format!("{}:{}", distro, tag))
-        .output()?;
+    // Writing the configuration again also fixes a broken entry in the registry
+    wsl.configure_distribution(&distro, default_uid, flags)?;
+    if flags != conf.wsl_distribution_flags {
+        println!("  Fixed: flags are reset to the defaults");
+        print_flags(flags);
+    }
 
-    if !output.status.success() {
-        bail!("Failed to create container");
+    wsl.terminate_distribution(&distro)?;
+
+    if problems == 0 {
+        println!("No problem found.");
+        Ok(())
+    } else {
+        bail!("{} problem(s) remain in {}", problems, distro)
     }
+}
+
+/// Uninstall distribution
+#[argopt::subcmd]
+fn uninstall(
+    /// Answer yes for all questions
+    #[opt(long, short)]
+    yes: bool,
+    ///
+    /// Uninstall all installed distributions
+    #[opt(long, conflicts_with = "pattern", conflicts_with = "distro")]
+    all: bool,
+    ///
+    /// Uninstall distributions whose names match the glob pattern (e.g. 'ubuntu-*')
+    #[opt(long = "match", conflicts_with = "distro")]
+    pattern: Option<String>,
+    ///
+    /// Name of distribution to uninstall
+    distro: Option<String>,
+) -> Result<()> {
+    let wsl = WSL::new();
 
-    let id = String::from_utf8(output.stdout)?.trim().to_owned();
+    let list = wsl.list_installed_distros()?;
 
-    defer! {
-        let stat = Command::new("docker")
-            .arg("rm")
-            .arg(&id)
-            .stderr(Stdio::inherit())
-            .output()
-            .unwrap();
-        if !stat.status.success() {
-            eprintln!("Failed to remove container");
+    let targets = if all {
+        list
+    } else if let Some(pattern) = &pattern {
+        let re = glob_to_regex(pattern)?;
+        list.into_iter().filter(|name| re.is_match(name)).collect()
+    } else if let Some(distro) = distro {
+        if !list.contains(&distro) {
+            bail!(Failure::NotFound(format!(
+                "Distribution {} is not installed",
+                distro
+            )));
         }
+        vec![distro]
+    } else {
+        bail!(Failure::Usage(
+            "Specify a distribution, --match <pattern> or --all".to_string()
+        ));
+    };
+
+    if targets.is_empty() {
+        println!("No distribution matched");
+        return Ok(());
     }
 
-    let mut temp_file = tempfile::NamedTempFile::new()?;
+    let prompt = if let [distro] = targets.as_slice() {
+        format!("Do you really want to uninstall {}", distro)
+    } else {
+        println!("The following distributions will be uninstalled:");
+        for distro in &targets {
+            println!("  {}", distro);
+        }
+        format!(
+            "Do you really want to uninstall {} distributions",
+            targets.len()
+        )
+    };
 
-    let mut child = Command::new("docker")
-        .arg("export")
-        .arg(&id)
-        .stdout(Stdio::piped())
-        .spawn()?;
+    if !yes && !dialoguer::Confirm::new().with_prompt(prompt).interact()? {
+        return Ok(());
+    }
 
-    let stdout = child.stdout.as_mut().unwrap();
+    let mut failed = vec![];
 
-    copy(
-        stdout,
-        &mut GzEncoder::new(File::create(&mut temp_file)?, Compression::fast()),
-    )?;
+    for distro in &targets {
+        println!("Uninstalling {}", distro);
+        if let Err(err) = uninstall_distro(&wsl, distro) {
+            eprintln!("{}: {:#}", distro, err);
+            failed.push(distro.as_str());
+        }
+    }
 
-    if !child.wait()?.success() {
-        bail!("Failed to save distribution tarball");
+    if !failed.is_empty() {
+        bail!(
+            "Uninstalled {} of {} distributions. Failed on: {}",
+            targets.len() - failed.len(),
+            targets.len(),
+            failed.join(", ")
+        );
     }
 
-    temp_file.persist(path)?;
+    println!("Complete!");
 
     Ok(())
 }
 
-fn parse_distro_name(distro: &str) -> Result<(String, String)> {
-    let re = regex::Regex::new("^([^:]+)(:([^:]+))?$")?;
+fn uninstall_distro(wsl: &WSL, distro: &str) -> Result<()> {
+    wsl.unregister_distribution(distro)?;
 
-    let cap = re
-        .captures(&distro)
-        .ok_or_else(|| anyhow!("failed to parse distribution name"))?;
+    let mut metadata = Metadata::load()?;
+    let removed = metadata.distros.remove(distro);
+    if removed.is_some() {
+        metadata.save()?;
+    }
 
-    let distro_name = &cap[1];
-    let distro_tag = cap.get(3).map(|r| r.as_str()).unwrap_or("latest");
+    let (image, digest) = removed.map_or((None, None), |m| (m.image, m.digest));
+    history::record(Action::Uninstall, distro, image, digest);
 
-    Ok((distro_name.to_string(), distro_tag.to_string()))
+    Ok(())
 }
 
-fn sanitize_path(s: &str) -> String {
-    s.chars().map(|c| if c == '/' { '-' } else { c }).collect()
+/// Convert a glob pattern with `*` and `?` to a regex matching the whole string
+fn glob_to_regex(pattern: &str) -> Result<regex::Regex> {
+    let re = pattern
+        .split('*')
+        .map(|part| {
+            part.split('?')
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join(".")
+        })
+        .collect::<Vec<_>>()
+        .join(".*");
+    Ok(regex::Regex::new(&format!("^{}$", re))?)
 }
 
-/// Download tarball of rootfs
+/// Show history of installations and uninstallations
 #[argopt::subcmd]
-fn download(distro: String) -> Result<()> {
-    let (distro_name, distro_tag) = parse_distro_name(&distro)?;
+fn history(
+    /// Print the history as JSON to stdout
+    #[opt(long)]
+    json: bool,
+) -> Result<()> {
+    let entries = history::load()?;
 
-    let fname = PathBuf::from(format!(
-        "{}-{}.tar.gz",
-        sanitize_path(&distro_name),
-        distro_tag
-    ));
-    get_distribution_rootfs_tar_gz(&distro_name, &distro_tag, &fname)?;
-    println!("Saved rootfs to {}", fname.display());
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    for entry in &entries {
+        println!(
+            "{}  {:<9}  {}  {}  {}",
+            entry
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            entry.action,
+            entry.distro,
+            entry.image.as_deref().unwrap_or("-"),
+            entry.digest.as_deref().unwrap_or("-"),
+        );
+    }
 
     Ok(())
 }
 
-/// Set default user of distribution
-#[argopt::subcmd(name = "set-default-user")]
-fn set_default_user(distro: String, user_name: String) -> Result<()> {
+/// Mount the VHD of distribution read-only in another distribution to inspect its files
+#[argopt::subcmd(name = "inspect-fs")]
+fn inspect_fs(
+    /// Distribution to mount the VHD in (defaults to the default distribution)
+    #[opt(long)]
+    using: Option<String>,
+    ///
+    /// Name of distribution to inspect
+    distro: String,
+    ///
+    /// Command to run in the root of the mounted file system (defaults to `ls -la`)
+    command: Vec<String>,
+) -> Result<()> {
     let wsl = WSL::new();
 
-    let uid = wsl.query_uid(&distro, &user_name)?;
-    let conf = wsl.get_distribution_configuration(&distro)?;
-    wsl.configure_distribution(&distro, uid as _, conf.wsl_distribution_flags)?;
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
+
+    let base_path = wsl
+        .base_path(&distro)?
+        .ok_or_else(|| anyhow!("Failed to find the directory of {}", distro))?;
+    let vhdx = base_path.join("ext4.vhdx");
+    if !vhdx.is_file() {
+        bail!(
+            "{} has no VHD. The files of WSL1 distributions are in {}",
+            distro,
+            base_path.join("rootfs").display()
+        );
+    }
+
+    // The VHD can't be mounted in the distribution itself
+    let helper = match using {
+        Some(helper) => {
+            if !wsl.is_distribution_registered(&helper) {
+                bail!(Failure::NotFound(format!(
+                    "Distribution {} is not installed",
+                    helper
+                )));
+            }
+            helper
+        }
+        None => wsl
+            .status()?
+            .default_distribution
+            .into_iter()
+            .chain(wsl.list_installed_distros()?)
+            .find(|name| !name.is_empty() && name != &distro)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Another distribution is needed to mount the VHD of {}",
+                    distro
+                )
+            })?,
+    };
+    if helper == distro {
+        bail!(Failure::Usage(
+            "--using must be another distribution than the one to inspect".to_string()
+        ));
+    }
+
+    // The VHD is locked while the distribution is running
+    if wsl.list_running_distros()?.contains(&distro) {
+        println!("Stopping {}...", distro);
+        wsl.terminate_distribution(&distro)?;
+    }
+
+    let name = format!("wsl-get-inspect-{}", std::process::id());
+    let mount_point = format!("/mnt/wsl/{}", name);
+
+    mount_vhd(&vhdx, &name)?;
+
+    let unmount_guard = {
+        let vhdx = vhdx.clone();
+        cleanup::on_exit(move || {
+            if let Err(err) = unmount_vhd(&vhdx) {
+                eprintln!(
+                    "Warning: {:#}\nRun `wsl.exe --unmount {}` to detach it",
+                    err,
+                    vhdx.display()
+                );
+            }
+        })
+    };
+
+    // Quoted as one script, so that the arguments reach the command as they are
+    let script = if command.is_empty() {
+        format!("ls -la {}", shell_quote(&mount_point))
+    } else {
+        let command = command
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!("cd {} && {}", shell_quote(&mount_point), command)
+    };
+
+    let code = wsl.exec(
+        &helper,
+        Some("root"),
+        &["sh".to_string(), "-c".to_string(), script],
+    )?;
+
+    // Exiting doesn't drop the guard
+    drop(unmount_guard);
+
+    if code != 0 {
+        eprintln!("Command exited with code {}", code);
+        std::process::exit(code);
+    }
 
     Ok(())
 }
 
-/// Uninstall distribution
+/// Show the overall state of WSL
 #[argopt::subcmd]
-fn uninstall(
+fn status(
+    /// Print the result as JSON to stdout
+    #[opt(long)]
+    json: bool,
+) -> Result<()> {
+    let wsl = WSL::new();
+
+    let status = wsl.status()?;
+    let default_version = match status.default_version {
+        Some(version) => Some(version),
+        None => wsl.default_version()?,
+    };
+    let running = wsl.list_running_distros()?;
+
+    // The Store version of WSL runs as WSLService instead of LxssManager
+    let mut services = vec![];
+    for service in WSL_SERVICES {
+        if let Some(state) = wsl.service_state(service)? {
+            services.push((*service, state));
+        }
+    }
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "default_distribution": status.default_distribution,
+                "default_version": default_version,
+                "running": running,
+                "services": services
+                    .iter()
+                    .map(|(service, state)| (service.to_string(), state.clone()))
+                    .collect::<std::collections::BTreeMap<_, _>>(),
+            })
+        );
+        return Ok(());
+    }
+
+    let or_unknown = |value: Option<String>| value.unwrap_or_else(|| "(unknown)".to_string());
+
+    println!(
+        "Default distribution: {}",
+        or_unknown(status.default_distribution)
+    );
+    println!(
+        "Default version:      {}",
+        or_unknown(default_version.map(|version| version.to_string()))
+    );
+    println!(
+        "Running:              {}",
+        if running.is_empty() {
+            "(none)".to_string()
+        } else {
+            running.join(", ")
+        }
+    );
+    for (service, state) in &services {
+        println!("{:<22}{}", format!("{}:", service), state);
+    }
+
+    Ok(())
+}
+
+const WSL_SERVICES: &[&str] = &["LxssManager", "WSLService"];
+
+/// Stop all running distributions and the WSL VM (`wsl.exe --shutdown`)
+#[argopt::subcmd(name = "stop-all")]
+fn stop_all(
     /// Answer yes for all questions
     #[opt(long, short)]
     yes: bool,
-    ///
-    /// Name of distribution to uninstall
-    distro: String,
 ) -> Result<()> {
     let wsl = WSL::new();
 
-    let list = wsl.list_installed_distros()?;
-
-    if !list.contains(&distro) {
-        bail!("Distribution {} is not installed", distro);
+    let running = wsl.list_running_distros()?;
+    if running.is_empty() {
+        println!("No distribution is running");
+    } else {
+        println!("The following distributions will be stopped:");
+        for distro in &running {
+            println!("  {}", distro);
+        }
     }
 
     if !yes
         && !dialoguer::Confirm::new()
-            .with_prompt(format!("Do you really want to uninstall {}", distro))
+            .with_prompt("Do you really want to shut down WSL?")
             .interact()?
     {
         return Ok(());
     }
 
-    println!("Uninstalling {}", distro);
-    wsl.unregister_distribution(&distro)?;
+    wsl.shutdown()?;
 
-    println!("Complete!");
+    println!("WSL has been shut down");
+
+    Ok(())
+}
+
+/// Check that wsl-get can work on this machine
+#[argopt::subcmd]
+fn doctor(
+    /// Print nothing and report the result only by the exit code
+    #[opt(long)]
+    check_only: bool,
+    ///
+    /// Print the result of each check as JSON to stdout
+    #[opt(long)]
+    json: bool,
+) -> Result<()> {
+    report_checks(&doctor::run_checks(), check_only, json)
+}
+
+/// Check that an installed distribution works as installed
+#[argopt::subcmd]
+fn verify(
+    /// Print the result of each check as JSON to stdout
+    #[opt(long)]
+    json: bool,
+    ///
+    /// Name of the installed distribution
+    distro: String,
+) -> Result<()> {
+    let wsl = WSL::new();
+    if !wsl.is_distribution_registered(&distro) {
+        bail!(Failure::NotFound(format!(
+            "Distribution {} is not installed",
+            distro
+        )));
+    }
+
+    let metadata = Metadata::load()?;
+    let user = metadata
+        .distros
+        .get(&distro)
+        .and_then(|meta| meta.user.as_deref());
+
+    report_checks(
+        &doctor::verify_distribution(&wsl, &distro, user),
+        false,
+        json,
+    )
+}
+
+/// Print the result of checks, and exit with 1 if any of them failed
+fn report_checks(checks: &[doctor::Check], check_only: bool, json: bool) -> Result<()> {
+    let healthy = checks
+        .iter()
+        .all(|check| check.status != doctor::Status::Fail);
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "healthy": healthy,
+                "checks": checks,
+            })
+        );
+    } else if !check_only {
+        for check in checks {
+            let mark = match check.status {
+                doctor::Status::Ok => "ok",
+                doctor::Status::Warn => "warn",
+                doctor::Status::Fail => "FAIL",
+            };
+            println!("[{}] {}: {}", mark, check.id, check.detail);
+        }
+    }
+
+    if !healthy {
+        if !check_only && !json {
+            eprintln!("Some checks failed");
+        }
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Show version information
+#[argopt::subcmd]
+fn version() -> Result<()> {
+    let wsl = WSL::new();
+
+    println!("wsl-get {}", env!("CARGO_PKG_VERSION"));
+    if let Some(commit) = option_env!("WSL_GET_GIT_COMMIT") {
+        println!("commit: {}", commit);
+    }
+    println!(
+        "wsl: {}",
+        wsl.version().unwrap_or_else(|_| "unknown".to_string())
+    );
+    println!(
+        "docker: {}",
+        docker_version().unwrap_or_else(|_| "unknown".to_string())
+    );
 
     Ok(())
 }
 
+fn docker_version() -> Result<String> {
+    let output = Command::new("docker")
+        .args(["version", "--format", "{{.Client.Version}}"])
+        .stderr(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Failed to get docker version");
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
 #[argopt::cmd_group(
     commands = [
         install,
         uninstall,
+        move_distro,
+        repair,
+        which,
+        info,
+        inspect_fs,
         set_default_user,
+        create_user,
+        config,
+        diff,
         list,
-        download
+        download,
+        build,
+        tags,
+        gc,
+        run,
+        shell,
+        exec_all,
+        open,
+        history,
+        status,
+        stop_all,
+        doctor,
+        verify,
+        version
     ]
 )]
-fn main() -> Result<()> {}
+fn dispatch() -> Result<()> {}
+
+fn main() {
+    // Check the arguments in advance, since clap exits with 1 on usage errors
+    if let Err(err) = Main_options_type::from_iter_safe(std::env::args_os()) {
+        if err.use_stderr() {
+            eprintln!("{}", err.message);
+            std::process::exit(Failure::Usage(String::new()).exit_code());
+        }
+        err.exit();
+    }
+
+    progress::set_mode(ProgressMode::Console);
+
+    if let Err(err) = cleanup::install_handler() {
+        eprintln!("Warning: failed to install Ctrl-C handler: {:#}", err);
+    }
+
+    if let Err(err) = dispatch() {
+        let code = error::exit_code(&err);
+        if progress::is_json() {
+            progress::emit(InstallEvent::Failed {
+                message: &format!("{:#}", err),
+                code,
+            });
+        } else {
+            eprintln!("Error: {:?}", err);
+        }
+        std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_dir_name_is_single_directory() {
+        for name in ["ubuntu", "my-distro_1.0", "My Distro"] {
+            assert!(check_data_dir_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", ".", "..", "a/b", "a\\b", "/abs", "../x", "C:\\x", "./"] {
+            assert!(check_data_dir_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn user_name_is_safe_for_shell() {
+        for name in ["alice", "_bob", "user-1", "machine$"] {
+            assert!(check_user_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "", "Alice", "1user", "-rf", "a b", "a;rm", "a'b", "$(id)", "a$b",
+        ] {
+            assert!(check_user_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn command_line_overrides_profile_flags() {
+        assert!(!profile_flag(false, false, false));
+        assert!(profile_flag(false, false, true));
+        assert!(profile_flag(true, false, false));
+        assert!(!profile_flag(false, true, true));
+    }
+
+    #[test]
+    fn user_prompt_only_when_needed() {
+        let alice = ["alice".to_string()];
+        let file = Some(Path::new("passwords.txt"));
+
+        // --no-user
+        assert!(!needs_user_prompt(false, &[], false, None));
+        assert!(!needs_user_prompt(false, &alice, false, None));
+
+        assert!(!needs_user_prompt(true, &alice, true, None));
+        assert!(!needs_user_prompt(true, &alice, false, file));
+        assert!(needs_user_prompt(true, &alice, false, None));
+        assert!(needs_user_prompt(true, &[], true, None));
+    }
+
+    #[test]
+    fn install_name_may_contain_spaces() {
+        for name in ["Ubuntu", "my-distro_1.0", "My Distro", "A  B"] {
+            assert!(check_install_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            " ",
+            " Leading",
+            "Trailing ",
+            "a/b",
+            "a\\b",
+            "a'b",
+            "a\"b",
+            "a\tb",
+        ] {
+            assert!(check_install_name(name).is_err(), "{:?}", name);
+        }
+    }
+}
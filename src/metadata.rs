@@ -0,0 +1,85 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::BufReader,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use directories::BaseDirs;
+use serde::{Deserialize, Serialize};
+
+/// Information about distributions installed by wsl-get
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    pub distros: BTreeMap<String, DistroMetadata>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DistroMetadata {
+    /// Directory that contains the VHD of the distribution
+    pub data_dir: PathBuf,
+    /// Image reference which the distribution was installed from
+    pub image: Option<String>,
+    /// Digest of the image (e.g. `ubuntu@sha256:...`)
+    pub digest: Option<String>,
+    /// Last completed stage of the installation
+    #[serde(default)]
+    pub stage: InstallStage,
+    /// User created on installation
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum InstallStage {
+    Registered,
+    UserCreated,
+    // Distributions recorded without stage were installed completely
+    #[default]
+    Complete,
+}
+
+/// Root directory where wsl-get stores distributions and its own data
+pub fn data_root() -> Result<PathBuf> {
+    let base_dirs = BaseDirs::new().ok_or_else(|| anyhow!("Failed to get home directory"))?;
+    Ok(base_dirs.cache_dir().join("wsl-get"))
+}
+
+/// Directory for downloaded data that can be fetched again.
+/// The leading dot keeps it apart from data directories, which `gc` cleans up.
+pub fn cache_root() -> Result<PathBuf> {
+    Ok(data_root()?.join(".cache"))
+}
+
+impl Metadata {
+    fn path() -> Result<PathBuf> {
+        Ok(data_root()?.join("metadata.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Modify metadata of a distribution and save it, if the distribution is recorded
+    pub fn update(distro_name: &str, f: impl FnOnce(&mut DistroMetadata)) -> Result<()> {
+        let mut metadata = Self::load()?;
+        if let Some(distro) = metadata.distros.get_mut(distro_name) {
+            f(distro);
+            metadata.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+
+use crate::{
+    archive::{normalize, ArchiveFormat},
+    output,
+};
+
+const MEDIA_TYPE_OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const MEDIA_TYPE_DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Index {
+    media_type: Option<String>,
+    manifests: Option<Vec<Descriptor>>,
+    layers: Option<Vec<Descriptor>>,
+}
+
+/// Image layout on disk as created by `buildah push oci:<dir>` or `skopeo copy oci:<dir>`
+pub struct OciLayout {
+    dir: PathBuf,
+}
+
+impl OciLayout {
+    pub fn open(dir: &Path) -> Result<Self> {
+        if !dir.join("oci-layout").is_file() {
+            bail!(
+                "`{}` is not an OCI image layout (missing `oci-layout` file)",
+                dir.display()
+            );
+        }
+        if !dir.join("index.json").is_file() {
+            bail!(
+                "`{}` is not an OCI image layout (missing `index.json`)",
+                dir.display()
+            );
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+        })
+    }
+
+    fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Malformed digest: {}", digest))?;
+        let path = self.dir.join("blobs").join(algorithm).join(hex);
+        if !path.is_file() {
+            bail!("Blob {} is missing in the layout", digest);
+        }
+        Ok(path)
+    }
+
+    /// Digest of the manifest tagged as `tag` (`org.opencontainers.image.ref.name`)
+    pub fn ref_digest(&self, tag: &str) -> Result<Option<String>> {
+        let index = self.read_index(&self.dir.join("index.json"))?;
+        Ok(index
+            .manifests
+            .unwrap_or_default()
+            .into_iter()
+            .find(|m| m.annotations.get(ANNOTATION_REF_NAME).map(String::as_str) == Some(tag))
+            .map(|m| m.digest))
+    }
+
+    fn read_index(&self, path: &Path) -> Result<Index> {
+        serde_json::from_reader(BufReader::new(File::open(path)?))
+            .with_context(|| format!("Malformed manifest: {}", path.display()))
+    }
+
+    /// Resolve `index.json` to the list of layer blobs, from the bottom layer
+    pub fn layers(&self) -> Result<Vec<PathBuf>> {
+        let mut index = self.read_index(&self.dir.join("index.json"))?;
+
+        // Follow nested indexes down to an image manifest.
+        loop {
+            if let Some(layers) = &index.layers {
+                return layers
+                    .iter()
+                    .map(|layer| self.blob_path(&layer.digest))
+                    .collect();
+            }
+
+            let manifests = index.manifests.unwrap_or_default();
+
+            // Prefer the manifest for linux/amd64 in multi-platform images
+            let manifest = manifests
+                .iter()
+                .find(|m| {
+                    m.platform
+                        .as_ref()
+                        .is_some_and(|p| p.os == "linux" && p.architecture == "amd64")
+                })
+                .or_else(|| manifests.first())
+                .ok_or_else(|| anyhow!("No manifest found in the layout"))?;
+
+            let path = self.blob_path(&manifest.digest)?;
+            index = self.read_index(&path)?;
+
+            let is_index = matches!(
+                manifest
+                    .media_type
+                    .as_deref()
+                    .or(index.media_type.as_deref()),
+                Some(MEDIA_TYPE_OCI_INDEX) | Some(MEDIA_TYPE_DOCKER_LIST)
+            );
+            if !is_index && index.layers.is_none() {
+                bail!("Manifest {} has no layers", manifest.digest);
+            }
+        }
+    }
+}
+
+/// Architecture of the host as named in image platforms (e.g. `amd64`)
+pub fn linux_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+}
+
+/// Copy `image` from its registry into the OCI image layout `dir` as `tag`, using skopeo.
+/// The layout is created if it doesn't exist, and other tags in it are kept.
+pub fn copy_to_layout(image: &str, dir: &Path, tag: &str, verbose: bool) -> Result<()> {
+    // skopeo picks the manifest for the host, which is Windows here
+    let mut cmd = Command::new("skopeo");
+    cmd.args([
+        "copy",
+        "--override-os",
+        "linux",
+        "--override-arch",
+        linux_arch(),
+    ]);
+    if !verbose {
+        cmd.arg("--quiet");
+    }
+    cmd.arg(format!("docker://{}", image))
+        .arg(format!("oci:{}:{}", dir.display(), tag))
+        .stdout(output::child_stdout());
+
+    let status = cmd.status().map_err(|err| match err.kind() {
+        ErrorKind::NotFound => {
+            anyhow!("skopeo is not found on PATH; it is required for --output-format oci")
+        }
+        _ => err.into(),
+    })?;
+
+    if !status.success() {
+        bail!("Failed to copy {} with skopeo", image);
+    }
+
+    Ok(())
+}
+
+fn open_layer(path: &Path) -> Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match ArchiveFormat::detect(path)? {
+        Some(ArchiveFormat::Gzip) => Box::new(GzDecoder::new(file)),
+        Some(ArchiveFormat::Zstd) => Box::new(zstd::Decoder::new(File::open(path)?)?),
+        Some(ArchiveFormat::Tar) => Box::new(file),
+        None => bail!("Unknown layer format: {}", path.display()),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// Merge image layers into a single rootfs tarball, applying whiteouts.
+/// `layers` are ordered from the bottom layer.
+pub fn flatten_layers(layers: &[PathBuf], writer: impl Write) -> Result<()> {
+    // First, find out which entries survive, from the top layer.
+    let mut seen = HashSet::new();
+    let mut deleted: Vec<PathBuf> = vec![];
+    let mut opaque: Vec<PathBuf> = vec![];
+    let mut survivors = vec![HashSet::new(); layers.len()];
+
+    for (i, layer) in layers.iter().enumerate().rev() {
+        // Whiteouts affect only lower layers
+        let mut new_deleted = vec![];
+        let mut new_opaque = vec![];
+
+        for entry in open_layer(layer)?.entries()? {
+            let path = normalize(&entry?.path()?);
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            if name == ".wh..wh..opq" {
+                // Opaque directory hides all contents of lower layers
+                new_opaque.push(path.parent().unwrap().to_owned());
+                continue;
+            }
+            if let Some(name) = name.strip_prefix(".wh.") {
+                new_deleted.push(path.with_file_name(name));
+                continue;
+            }
+
+            if deleted.iter().any(|p| path.starts_with(p))
+                || opaque.iter().any(|p| path.starts_with(p) && &path != p)
+            {
+                continue;
+            }
+            if seen.insert(path.clone()) {
+                survivors[i].insert(path);
+            }
+        }
+
+        deleted.extend(new_deleted);
+        opaque.extend(new_opaque);
+    }
+
+    // Then, write them from the bottom layer so that parents come first.
+    let mut builder = tar::Builder::new(writer);
+
+    for (i, layer) in layers.iter().enumerate() {
+        for entry in open_layer(layer)?.entries()? {
+            let mut entry = entry?;
+            let path = normalize(&entry.path()?);
+            if !survivors[i].contains(&path) {
+                continue;
+            }
+
+            let mut header = entry.header().clone();
+            if let Some(link_name) = entry.link_name()? {
+                let link_name = link_name.into_owned();
+                builder.append_link(&mut header, &path, link_name)?;
+            } else {
+                builder.append_data(&mut header, &path, &mut entry)?;
+            }
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use std::{
+    io,
+    process::Stdio,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Switch to JSON output. Messages for humans are moved to stderr,
+/// so that stdout contains only the JSON result.
+pub fn set_json(enabled: bool) {
+    JSON.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Suppress progress messages and the output of child processes,
+/// for when progress is reported in another way
+pub fn set_quiet(enabled: bool) {
+    QUIET.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Stdout for child processes whose output is shown to users
+pub fn child_stdout() -> Stdio {
+    if is_quiet() {
+        Stdio::null()
+    } else if is_json() {
+        io::stderr().into()
+    } else {
+        Stdio::inherit()
+    }
+}
+
+/// Print a progress message
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::output::is_quiet() {
+            // Reported as progress events instead
+        } else if $crate::output::is_json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use status;
@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::wsl::WSL;
+
+/// Package databases and the commands to list installed packages as `<name> <version>`
+const PACKAGE_LISTERS: &[(&str, &str)] = &[
+    (
+        "/usr/bin/dpkg-query",
+        r"dpkg-query -W -f='${Package} ${Version}\n'",
+    ),
+    (
+        "/usr/bin/rpm",
+        r"rpm -qa --queryformat '%{NAME} %{VERSION}-%{RELEASE}\n'",
+    ),
+    ("/usr/bin/pacman", "pacman -Q"),
+    ("/sbin/apk", "apk info"),
+];
+
+/// Installed packages of the distribution, mapped to their versions if known
+pub fn list_packages(wsl: &WSL, distro_name: &str) -> Result<BTreeMap<String, Option<String>>> {
+    let mut command = None;
+    for (path, cmd) in PACKAGE_LISTERS {
+        if wsl.file_exists(distro_name, path)? {
+            command = Some(*cmd);
+            break;
+        }
+    }
+    let command =
+        command.ok_or_else(|| anyhow!("No supported package manager found in {}", distro_name))?;
+
+    let output = wsl.run_capture(distro_name, command)?;
+    if output.exit_code != 0 {
+        bail!(
+            "Failed to list packages of {}\n{}",
+            distro_name,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?.to_string();
+            Some((name, fields.next().map(|version| version.to_string())))
+        })
+        .collect())
+}
@@ -0,0 +1,243 @@
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, RwLock,
+    },
+};
+
+use anyhow::{bail, Result};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+use crate::output::{self, status};
+
+/// Version of the JSON progress schema, bumped on incompatible changes
+const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// Steps of pulling and installing a distribution.
+/// In JSON, the variant is the `event` field in snake_case and the fields are kept as they are.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum InstallEvent<'a> {
+    /// Installation started from `source` (an image, file or distribution)
+    Installing {
+        source: &'a str,
+        install_name: &'a str,
+    },
+    /// Continuing an installation that failed halfway
+    Resuming {
+        install_name: &'a str,
+    },
+    Pulling,
+    Pulled {
+        image: &'a str,
+        runtime: &'a str,
+    },
+    /// Using the rootfs cached by a previous pull
+    CachedRootfs {
+        image: &'a str,
+    },
+    Building,
+    Flattening {
+        layers: usize,
+    },
+    /// Exporting the rootfs from a container, distribution or directory
+    Exporting {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        source: Option<&'a str>,
+    },
+    Exported {
+        bytes: u64,
+    },
+    Registering,
+    /// Importing is in progress; `bytes` is the current size of the VHD if it exists
+    Importing {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes: Option<u64>,
+    },
+    Imported,
+    /// Importing was stopped by the timeout, and the error follows
+    ImportTimedOut,
+    EnablingSystemd,
+    /// Waiting for systemd to boot after restarting the distribution
+    WaitingForSystemd,
+    SettingHostname {
+        hostname: &'a str,
+    },
+    SettingDefaultCommand {
+        command: &'a str,
+    },
+    CreatingUser {
+        name: &'a str,
+    },
+    SetDefault {
+        install_name: &'a str,
+    },
+    Complete,
+    Warning {
+        message: &'a str,
+    },
+    /// The command failed with the exit `code`
+    Failed {
+        message: &'a str,
+        code: i32,
+    },
+}
+
+/// Receiver of progress events, to show them in another UI than the console
+pub trait InstallProgress: Send + Sync {
+    fn event(&self, event: &InstallEvent);
+}
+
+static REPORTER: RwLock<Option<Box<dyn InstallProgress>>> = RwLock::new(None);
+
+/// Send progress events to `reporter`. Events are dropped until this is called.
+pub fn set_reporter(reporter: Box<dyn InstallProgress>) {
+    *REPORTER.write().unwrap_or_else(|err| err.into_inner()) = Some(reporter);
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// How progress is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Messages and spinners for humans
+    Console,
+    /// Newline-delimited JSON events to stderr
+    Json,
+}
+
+impl FromStr for ProgressMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "console" => Self::Console,
+            "json" => Self::Json,
+            _ => bail!("Unknown progress mode: {} (expected console or json)", s),
+        })
+    }
+}
+
+/// Switch the reporter to `mode`. The JSON mode also suppresses progress messages to stdout.
+pub fn set_mode(mode: ProgressMode) {
+    match mode {
+        ProgressMode::Console => set_reporter(Box::<ConsoleProgress>::default()),
+        ProgressMode::Json => set_reporter(Box::new(JsonProgress)),
+    }
+    output::set_quiet(mode == ProgressMode::Json);
+    JSON.store(mode == ProgressMode::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+pub fn emit(event: InstallEvent) {
+    let reporter = REPORTER.read().unwrap_or_else(|err| err.into_inner());
+    if let Some(reporter) = reporter.as_ref() {
+        reporter.event(&event);
+    }
+}
+
+/// Print progress to the console, as the command line interface does
+#[derive(Default)]
+pub struct ConsoleProgress {
+    spinner: Mutex<Option<ProgressBar>>,
+}
+
+impl InstallProgress for ConsoleProgress {
+    fn event(&self, event: &InstallEvent) {
+        match event {
+            InstallEvent::Installing {
+                source,
+                install_name,
+            } => status!("Installing {} as {}", source, install_name),
+            InstallEvent::Resuming { install_name } => {
+                status!("Resuming installation of {}", install_name)
+            }
+            InstallEvent::Pulling => status!("Pulling image..."),
+            InstallEvent::Pulled { image, runtime } => {
+                status!("Pulled {} with {}", image, runtime)
+            }
+            InstallEvent::CachedRootfs { image } => status!("Using cached rootfs of {}", image),
+            InstallEvent::Building => status!("Building image..."),
+            InstallEvent::Flattening { layers } => status!("Flattening {} layers...", layers),
+            InstallEvent::Exporting { source: None } => status!("Exporting rootfs..."),
+            InstallEvent::Exporting {
+                source: Some(source),
+            } => status!("Exporting {}...", source),
+            InstallEvent::Exported { bytes } => status!("Exported {}", HumanBytes(*bytes)),
+            InstallEvent::Registering => status!("Registering distribution..."),
+            InstallEvent::Importing { bytes } => {
+                let mut spinner = self.spinner.lock().unwrap_or_else(|err| err.into_inner());
+                let spinner = spinner.get_or_insert_with(|| {
+                    let spinner = ProgressBar::new_spinner();
+                    spinner.set_style(
+                        ProgressStyle::default_spinner().template("{spinner} {msg} [{elapsed}]"),
+                    );
+                    // Events come at most once a second
+                    spinner.enable_steady_tick(100);
+                    spinner
+                });
+                match bytes {
+                    Some(bytes) => {
+                        spinner.set_message(format!("Importing... {}", HumanBytes(*bytes)))
+                    }
+                    None => spinner.set_message("Importing..."),
+                }
+                spinner.tick();
+            }
+            InstallEvent::Imported | InstallEvent::ImportTimedOut => {
+                let spinner = self
+                    .spinner
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .take();
+                if let Some(spinner) = spinner {
+                    spinner.finish_and_clear();
+                }
+            }
+            InstallEvent::EnablingSystemd => status!("Enabling systemd..."),
+            InstallEvent::WaitingForSystemd => status!("Waiting for systemd to start..."),
+            InstallEvent::SettingHostname { hostname } => {
+                status!("Setting hostname to {}...", hostname)
+            }
+            InstallEvent::SettingDefaultCommand { command } => {
+                status!("Setting default command to `{}`...", command)
+            }
+            InstallEvent::CreatingUser { name } => status!("Creating user {}...", name),
+            InstallEvent::SetDefault { install_name } => {
+                status!("Default distribution is now {}", install_name)
+            }
+            InstallEvent::Complete => status!("Complete!"),
+            InstallEvent::Warning { message } => status!("Warning: {}", message),
+            // main prints the error with its causes
+            InstallEvent::Failed { .. } => {}
+        }
+    }
+}
+
+/// Print each event as a line of JSON to stderr, for front-ends that run wsl-get as a process
+pub struct JsonProgress;
+
+#[derive(Serialize)]
+struct JsonEvent<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: &'a InstallEvent<'a>,
+}
+
+impl InstallProgress for JsonProgress {
+    fn event(&self, event: &InstallEvent) {
+        let line = serde_json::to_string(&JsonEvent {
+            version: JSON_SCHEMA_VERSION,
+            event,
+        })
+        .unwrap();
+        // Progress is best effort; a closed stderr must not fail the installation
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+}
@@ -0,0 +1,827 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, iter,
+    path::Path,
+    process::Command,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    credentials::{self, Credential},
+    metadata::cache_root,
+    output::status,
+    sha256,
+};
+
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// Image repository on a container registry
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repository {
+    pub registry: String,
+    pub name: String,
+}
+
+impl Repository {
+    /// Parse an image name as docker does (e.g. `ubuntu`, `user/image`, `ghcr.io/owner/image`)
+    pub fn parse(image: &str) -> Self {
+        let (registry, name) = match image.split_once('/') {
+            // `docker.io/library/ubuntu` is the same as `ubuntu`
+            Some(("docker.io" | "index.docker.io", rest)) => {
+                (DOCKER_HUB_REGISTRY.to_string(), rest.to_string())
+            }
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), rest.to_string())
+            }
+            _ => (DOCKER_HUB_REGISTRY.to_string(), image.to_string()),
+        };
+
+        let name = if registry == DOCKER_HUB_REGISTRY && !name.contains('/') {
+            format!("library/{}", name)
+        } else {
+            name
+        };
+
+        Self { registry, name }
+    }
+
+    pub fn is_docker_hub(&self) -> bool {
+        self.registry == DOCKER_HUB_REGISTRY
+    }
+}
+
+/// Validate a registry mirror and normalize it to the form used in image references
+/// (e.g. `https://mirror.example.com/` -> `mirror.example.com`)
+pub fn parse_mirror(mirror: &str) -> Result<String> {
+    let re =
+        regex::Regex::new(r"^(?:https?://)?([A-Za-z0-9.-]+(?::[0-9]+)?(?:/[A-Za-z0-9._-]+)*)/?$")?;
+
+    let cap = re
+        .captures(mirror)
+        .ok_or_else(|| anyhow!("Invalid registry mirror: {}", mirror))?;
+
+    Ok(cap[1].to_string())
+}
+
+/// Bytes per second that curl may receive, 0 for no limit
+static MAX_RATE: AtomicU64 = AtomicU64::new(0);
+/// Bytes received by curl and the milliseconds it took, for the average rate
+static RECEIVED_BYTES: AtomicU64 = AtomicU64::new(0);
+static RECEIVED_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+/// Limit the download speed of each request to `rate` bytes per second
+pub fn set_max_rate(rate: Option<u64>) {
+    MAX_RATE.store(rate.unwrap_or(0), Ordering::Relaxed);
+}
+
+pub fn is_rate_limited() -> bool {
+    MAX_RATE.load(Ordering::Relaxed) > 0
+}
+
+fn record_transfer(bytes: u64, elapsed: Duration) {
+    RECEIVED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    RECEIVED_MILLIS.fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Total bytes received from registries and the average bytes per second, if any
+pub fn transfer_stats() -> Option<(u64, u64)> {
+    let bytes = RECEIVED_BYTES.load(Ordering::Relaxed);
+    let millis = RECEIVED_MILLIS.load(Ordering::Relaxed);
+    (bytes > 0).then(|| (bytes, bytes * 1000 / millis.max(1)))
+}
+
+fn curl() -> Command {
+    let mut cmd = Command::new("curl");
+    cmd.args(["--silent", "--show-error"]);
+    let rate = MAX_RATE.load(Ordering::Relaxed);
+    if rate > 0 {
+        cmd.arg("--limit-rate").arg(rate.to_string());
+    }
+    cmd
+}
+
+struct Response {
+    status: u32,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// Send a GET request using curl, which is shipped with Windows 10 and later
+fn http_get(url: &str, headers: &[(&str, &str)]) -> Result<Response> {
+    let mut cmd = curl();
+    // Without this, the response of a proxy to CONNECT is dumped before the real one
+    cmd.args(["--dump-header", "-", "--suppress-connect-headers"]);
+    for (key, value) in headers {
+        cmd.arg("--header").arg(format!("{}: {}", key, value));
+    }
+    cmd.arg(url);
+
+    let start = Instant::now();
+    let output = cmd.output()?;
+    record_transfer(output.stdout.len() as u64, start.elapsed());
+
+    if !output.status.success() {
+        bail!(
+            "Failed to request {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+
+    parse_response(&output.stdout).ok_or_else(|| anyhow!("Invalid response from {}", url))
+}
+
+/// Split the output of `curl --dump-header -` into the final response.
+/// Header blocks of interim responses (`100 Continue`, or the `200 Connection established`
+/// of a proxy on curl versions without --suppress-connect-headers) come first, and are skipped.
+fn parse_response(mut raw: &[u8]) -> Option<Response> {
+    let (head, body) = loop {
+        let pos = raw.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let (head, rest) = (&raw[..pos], &raw[pos + 4..]);
+        if !rest.starts_with(b"HTTP/") {
+            break (head, rest);
+        }
+        raw = rest;
+    };
+
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())?;
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    Some(Response {
+        status,
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+/// Parse parameters of `WWW-Authenticate: Bearer realm="...",service="...",scope="..."`
+fn parse_bearer_challenge(challenge: &str) -> Option<HashMap<String, String>> {
+    let params = challenge.strip_prefix("Bearer ")?;
+    let re = regex::Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+    Some(
+        re.captures_iter(params)
+            .map(|cap| (cap[1].to_string(), cap[2].to_string()))
+            .collect(),
+    )
+}
+
+/// Get a pull token as requested by the registry, anonymously if `credential` is not given
+fn get_token(challenge: &str, credential: Option<&Credential>) -> Result<String> {
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        token: Option<String>,
+        access_token: Option<String>,
+    }
+
+    let params = parse_bearer_challenge(challenge)
+        .ok_or_else(|| anyhow!("Unsupported authentication: {}", challenge))?;
+
+    let realm = params
+        .get("realm")
+        .ok_or_else(|| anyhow!("Missing realm in authentication challenge"))?;
+
+    let query = ["service", "scope"]
+        .iter()
+        .filter_map(|key| params.get(*key).map(|value| format!("{}={}", key, value)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let basic_auth = credential.map(Credential::basic_auth);
+    let headers = basic_auth
+        .iter()
+        .map(|auth| ("Authorization", auth.as_str()))
+        .collect::<Vec<_>>();
+
+    let res = http_get(&format!("{}?{}", realm, query), &headers)?;
+
+    if res.status != 200 {
+        bail!("Failed to get token from {} (status {})", realm, res.status);
+    }
+
+    let token: TokenResponse = serde_json::from_slice(&res.body)?;
+
+    token
+        .token
+        .or(token.access_token)
+        .ok_or_else(|| anyhow!("No token in response from {}", realm))
+}
+
+/// Extract the URL of `Link: </v2/...?last=...>; rel="next"`
+fn next_link(res: &Response) -> Option<String> {
+    let link = res.headers.get("link")?;
+    if !link.contains(r#"rel="next""#) {
+        return None;
+    }
+    let start = link.find('<')? + 1;
+    let end = link.find('>')?;
+    Some(link[start..end].to_string())
+}
+
+/// Registry API client, authenticating with docker's credentials (or anonymously) when required
+struct Client<'a> {
+    repo: &'a Repository,
+    /// Value of the `Authorization` header
+    auth: Option<String>,
+}
+
+impl<'a> Client<'a> {
+    fn new(repo: &'a Repository) -> Self {
+        Self { repo, auth: None }
+    }
+
+    fn authenticate(&self, challenge: &str) -> Result<String> {
+        let credential = credentials::lookup(&self.repo.registry).unwrap_or_else(|err| {
+            status!("Warning: failed to read docker credentials: {:#}", err);
+            None
+        });
+
+        if challenge.starts_with("Basic") {
+            let credential = credential.ok_or_else(|| {
+                anyhow!(
+                    "{} requires authentication. Run `docker login {}` first",
+                    self.repo.registry,
+                    self.repo.registry
+                )
+            })?;
+            return Ok(credential.basic_auth());
+        }
+
+        Ok(format!(
+            "Bearer {}",
+            get_token(challenge, credential.as_ref())?
+        ))
+    }
+
+    /// GET a URL or a path on the registry
+    fn get(&mut self, url: &str, accept: Option<&str>) -> Result<Response> {
+        let url = if url.starts_with('/') {
+            format!("https://{}{}", self.repo.registry, url)
+        } else {
+            url.to_string()
+        };
+
+        loop {
+            let mut headers = vec![];
+            if let Some(auth) = &self.auth {
+                headers.push(("Authorization", auth.as_str()));
+            }
+            if let Some(accept) = accept {
+                headers.push(("Accept", accept));
+            }
+
+            let res = http_get(&url, &headers)?;
+
+            if res.status == 401 && self.auth.is_none() {
+                let challenge = res
+                    .headers
+                    .get("www-authenticate")
+                    .ok_or_else(|| anyhow!("Registry requires authentication"))?;
+                self.auth = Some(self.authenticate(challenge)?);
+                continue;
+            }
+
+            // Blobs are often redirected to a storage, which doesn't need the token
+            if matches!(res.status, 301 | 302 | 303 | 307 | 308) {
+                if let Some(location) = res.headers.get("location") {
+                    return http_get(location, &[]);
+                }
+            }
+
+            return Ok(res);
+        }
+    }
+
+    /// Save the blob to `path`, following the redirect to the storage.
+    /// The authorization is not sent to the storage, since curl drops it on other hosts.
+    fn download_blob(&self, digest: &str, path: &Path) -> Result<u64> {
+        let mut cmd = curl();
+        cmd.args(["--fail", "--location", "--output"]).arg(path);
+        if let Some(auth) = &self.auth {
+            cmd.arg("--header").arg(format!("Authorization: {}", auth));
+        }
+        cmd.arg(format!(
+            "https://{}/v2/{}/blobs/{}",
+            self.repo.registry, self.repo.name, digest
+        ));
+
+        let start = Instant::now();
+        let output = cmd.output()?;
+        if !output.status.success() {
+            bail!(
+                "Failed to download {}: {}",
+                digest,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+
+        let size = fs::metadata(path)?.len();
+        record_transfer(size, start.elapsed());
+        Ok(size)
+    }
+}
+
+/// List all tags of the repository
+pub fn list_tags(repo: &Repository) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct TagList {
+        tags: Option<Vec<String>>,
+    }
+
+    let mut client = Client::new(repo);
+    let mut url = format!("/v2/{}/tags/list", repo.name);
+    let mut ret = vec![];
+
+    loop {
+        let res = client.get(&url, None)?;
+
+        if res.status == 404 {
+            bail!(
+                "Repository `{}` is not found on {}",
+                repo.name,
+                repo.registry
+            );
+        }
+
+        if res.status != 200 {
+            bail!("Failed to list tags (status {})", res.status);
+        }
+
+        let tags: TagList = serde_json::from_slice(&res.body)?;
+        ret.extend(tags.tags.unwrap_or_default());
+
+        match next_link(&res) {
+            Some(next) => url = next,
+            None => break,
+        }
+    }
+
+    Ok(ret)
+}
+
+const MANIFEST_MEDIA_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// Platforms and annotations of a tag, read from its manifest
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TagInfo {
+    /// e.g. `linux/amd64`, `linux/arm64`
+    pub platforms: Vec<String>,
+    pub annotations: BTreeMap<String, String>,
+    /// Seconds since the UNIX epoch
+    fetched_at: u64,
+}
+
+impl TagInfo {
+    pub fn has_arch(&self, arch: &str) -> bool {
+        self.platforms
+            .iter()
+            .any(|platform| platform.split('/').nth(1) == Some(arch))
+    }
+}
+
+fn fetch_tag_info(client: &mut Client, tag: &str) -> Result<Option<TagInfo>> {
+    #[derive(Deserialize)]
+    struct Platform {
+        os: String,
+        architecture: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Descriptor {
+        digest: String,
+        platform: Option<Platform>,
+        #[serde(default)]
+        annotations: BTreeMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Manifest {
+        manifests: Option<Vec<Descriptor>>,
+        config: Option<Descriptor>,
+        #[serde(default)]
+        annotations: BTreeMap<String, String>,
+    }
+
+    let name = client.repo.name.clone();
+    let res = client.get(
+        &format!("/v2/{}/manifests/{}", name, tag),
+        Some(MANIFEST_MEDIA_TYPES),
+    )?;
+
+    if res.status == 429 {
+        return Ok(None);
+    }
+    if res.status != 200 {
+        bail!("Failed to get manifest of {} (status {})", tag, res.status);
+    }
+
+    let manifest: Manifest = serde_json::from_slice(&res.body)?;
+    let mut info = TagInfo {
+        annotations: manifest.annotations,
+        ..Default::default()
+    };
+
+    if let Some(manifests) = manifest.manifests {
+        for m in manifests {
+            if let Some(p) = m.platform {
+                // Skip attestations, which are listed as `unknown/unknown`
+                if p.os != "unknown" {
+                    info.platforms.push(format!("{}/{}", p.os, p.architecture));
+                }
+            }
+            info.annotations.extend(m.annotations);
+        }
+    } else if let Some(config) = manifest.config {
+        // Single-platform image has the platform in its config
+        let res = client.get(&format!("/v2/{}/blobs/{}", name, config.digest), None)?;
+        if res.status == 429 {
+            return Ok(None);
+        }
+        if res.status != 200 {
+            bail!("Failed to get config of {} (status {})", tag, res.status);
+        }
+        let p: Platform = serde_json::from_slice(&res.body)?;
+        info.platforms.push(format!("{}/{}", p.os, p.architecture));
+    }
+
+    Ok(Some(info))
+}
+
+/// How long platforms and annotations of a tag are reused
+const MANIFEST_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Read platforms and annotations of the tags, using the cache if possible.
+/// When rate-limited by the registry, returns the tags checked so far.
+pub fn inspect_tags(repo: &Repository, tags: &[String]) -> Result<BTreeMap<String, TagInfo>> {
+    let path = cache_root()?.join("manifests").join(cache_file_name(repo));
+
+    let mut cache: BTreeMap<String, TagInfo> = fs::read(&path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut client = Client::new(repo);
+    let mut ret = BTreeMap::new();
+
+    for tag in tags {
+        match cache.get(tag) {
+            Some(info) if now.saturating_sub(info.fetched_at) < MANIFEST_CACHE_TTL.as_secs() => {
+                ret.insert(tag.clone(), info.clone());
+                continue;
+            }
+            _ => {}
+        }
+
+        match fetch_tag_info(&mut client, tag)? {
+            Some(mut info) => {
+                info.fetched_at = now;
+                cache.insert(tag.clone(), info.clone());
+                ret.insert(tag.clone(), info);
+            }
+            None => {
+                status!(
+                    "Warning: rate-limited by {}; showing {} of {} tags checked so far",
+                    repo.registry,
+                    ret.len(),
+                    tags.len()
+                );
+                break;
+            }
+        }
+    }
+
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(&path, serde_json::to_vec(&cache)?)?;
+
+    Ok(ret)
+}
+
+fn cache_file_name(repo: &Repository) -> String {
+    format!("{}_{}.json", repo.registry, repo.name).replace(
+        |c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '_',
+        "-",
+    )
+}
+
+/// How long a fetched tag list is reused
+const TAGS_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// List all tags of the repository, reusing the result fetched recently
+pub fn list_tags_cached(repo: &Repository) -> Result<Vec<String>> {
+    let path = cache_root()?.join("tags").join(cache_file_name(repo));
+
+    let is_fresh = fs::metadata(&path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age < TAGS_CACHE_TTL);
+
+    if is_fresh {
+        if let Ok(tags) = serde_json::from_slice(&fs::read(&path)?) {
+            return Ok(tags);
+        }
+    }
+
+    let tags = list_tags(repo)?;
+
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(&path, serde_json::to_vec(&tags)?)?;
+
+    Ok(tags)
+}
+
+/// Descriptor of a blob in a manifest
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlobDescriptor {
+    media_type: Option<String>,
+    digest: String,
+    size: u64,
+    platform: Option<BlobPlatform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlobPlatform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageManifest {
+    media_type: Option<String>,
+    manifests: Option<Vec<BlobDescriptor>>,
+    config: Option<BlobDescriptor>,
+    #[serde(default)]
+    layers: Vec<BlobDescriptor>,
+}
+
+/// Path of a blob in an OCI image layout
+fn layout_blob_path(dir: &Path, digest: &str) -> Result<std::path::PathBuf> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Malformed digest: {}", digest))?;
+    Ok(dir.join("blobs").join(algorithm).join(hex))
+}
+
+/// Copy the image `reference` (a tag or digest) for `linux/<arch>` into the OCI image layout
+/// `dir` as `tag`, with curl instead of skopeo so that `set_max_rate` applies.
+/// Manifests and blobs are verified by their sha256 digests as skopeo does, and blobs
+/// already in the layout are not downloaded again.
+pub fn copy_to_layout(
+    repo: &Repository,
+    reference: &str,
+    arch: &str,
+    dir: &Path,
+    tag: &str,
+) -> Result<()> {
+    let mut client = Client::new(repo);
+
+    let fetch_manifest = |client: &mut Client, reference: &str| -> Result<Response> {
+        let res = client.get(
+            &format!("/v2/{}/manifests/{}", repo.name, reference),
+            Some(MANIFEST_MEDIA_TYPES),
+        )?;
+        if res.status != 200 {
+            bail!(
+                "Failed to get manifest of {} (status {})",
+                reference,
+                res.status
+            );
+        }
+        Ok(res)
+    };
+
+    let res = fetch_manifest(&mut client, reference)?;
+    let mut media_type = res.headers.get("content-type").cloned().unwrap_or_default();
+    let mut digest = res
+        .headers
+        .get("docker-content-digest")
+        .cloned()
+        .or_else(|| Some(reference.to_string()).filter(|r| r.starts_with("sha256:")));
+    let mut body = res.body;
+    if let Some(digest) = &digest {
+        verify_digest(
+            &format!("Manifest of {}", reference),
+            digest,
+            &sha256::digest(&body),
+        )?;
+    }
+    let mut manifest: ImageManifest = serde_json::from_slice(&body)?;
+
+    // Pick the manifest of the platform from a multi-platform index
+    if let Some(manifests) = &manifest.manifests {
+        let entry = manifests
+            .iter()
+            .find(|m| {
+                m.platform
+                    .as_ref()
+                    .is_some_and(|p| p.os == "linux" && p.architecture == arch)
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "{}:{} has no image for linux/{}",
+                    repo.name,
+                    reference,
+                    arch
+                )
+            })?;
+
+        let res = fetch_manifest(&mut client, &entry.digest)?;
+        if res.body.len() as u64 != entry.size {
+            bail!("Size of manifest {} does not match", entry.digest);
+        }
+        verify_digest(
+            &format!("Manifest {}", entry.digest),
+            &entry.digest,
+            &sha256::digest(&res.body),
+        )?;
+        digest = Some(entry.digest.clone());
+        media_type = entry
+            .media_type
+            .clone()
+            .or_else(|| res.headers.get("content-type").cloned())
+            .unwrap_or_default();
+        body = res.body;
+        manifest = serde_json::from_slice(&body)?;
+    }
+
+    let digest =
+        digest.ok_or_else(|| anyhow!("Registry did not return the digest of {}", reference))?;
+    let media_type = manifest.media_type.clone().unwrap_or(media_type);
+
+    let config = manifest
+        .config
+        .as_ref()
+        .ok_or_else(|| anyhow!("Manifest {} has no config", digest))?;
+
+    fs::create_dir_all(dir)?;
+    if !dir.join("oci-layout").is_file() {
+        fs::write(dir.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+    }
+
+    for blob in iter::once(config).chain(&manifest.layers) {
+        let path = layout_blob_path(dir, &blob.digest)?;
+        if fs::metadata(&path).is_ok_and(|meta| meta.len() == blob.size)
+            && sha256::file_digest(&path)? == blob.digest
+        {
+            continue;
+        }
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        let temp_path = tempfile::NamedTempFile::new_in(path.parent().unwrap())?.into_temp_path();
+        let size = client.download_blob(&blob.digest, &temp_path)?;
+        // The temporary file is removed on drop if either check fails
+        if size != blob.size {
+            bail!(
+                "Size of blob {} is {} instead of {}",
+                blob.digest,
+                size,
+                blob.size
+            );
+        }
+        verify_digest(
+            &format!("Blob {}", blob.digest),
+            &blob.digest,
+            &sha256::file_digest(&temp_path)?,
+        )?;
+        temp_path.persist(&path)?;
+    }
+
+    let manifest_path = layout_blob_path(dir, &digest)?;
+    fs::create_dir_all(manifest_path.parent().unwrap())?;
+    fs::write(&manifest_path, &body)?;
+
+    tag_in_index(dir, tag, &media_type, &digest, body.len() as u64)
+}
+
+/// Fail unless `actual` (a sha256 digest) is `expected`
+fn verify_digest(what: &str, expected: &str, actual: &str) -> Result<()> {
+    if !expected.starts_with("sha256:") {
+        bail!("{} has an unsupported digest {}", what, expected);
+    }
+    if actual != expected {
+        bail!("{} is corrupted: its digest is {}", what, actual);
+    }
+    Ok(())
+}
+
+/// Point `tag` in the `index.json` of the layout to the manifest, keeping the other tags
+fn tag_in_index(dir: &Path, tag: &str, media_type: &str, digest: &str, size: u64) -> Result<()> {
+    let index_path = dir.join("index.json");
+    let mut index: serde_json::Value = match fs::read(&index_path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(_) => serde_json::json!({ "schemaVersion": 2, "manifests": [] }),
+    };
+
+    let manifests = index["manifests"]
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("Malformed {}", index_path.display()))?;
+    manifests.retain(|m| m["annotations"][ANNOTATION_REF_NAME] != tag);
+    manifests.push(serde_json::json!({
+        "mediaType": media_type,
+        "digest": digest,
+        "size": size,
+        "annotations": { ANNOTATION_REF_NAME: tag },
+    }));
+
+    fs::write(&index_path, serde_json::to_vec_pretty(&index)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_sha256_digests() {
+        let digest = sha256::digest(b"{}");
+        assert!(verify_digest("Blob", &digest, &sha256::digest(b"{}")).is_ok());
+
+        let err = verify_digest("Blob", &digest, &sha256::digest(b"[]")).unwrap_err();
+        assert!(err.to_string().contains("corrupted"));
+
+        assert!(verify_digest("Blob", "sha512:abc", "sha512:abc").is_err());
+    }
+
+    #[test]
+    fn parse_response_skips_interim_headers() {
+        let response = parse_response(
+            b"HTTP/1.1 200 Connection established\r\n\r\n\
+              HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 401 Unauthorized\r\n\
+              WWW-Authenticate: Bearer realm=\"https://auth.docker.io/token\"\r\n\
+              Content-Type: application/json\r\n\r\n\
+              {\"errors\":[]}",
+        )
+        .unwrap();
+
+        assert_eq!(response.status, 401);
+        assert_eq!(
+            response.headers["www-authenticate"],
+            "Bearer realm=\"https://auth.docker.io/token\""
+        );
+        assert_eq!(response.body, b"{\"errors\":[]}");
+    }
+
+    #[test]
+    fn parse_response_plain() {
+        let response = parse_response(b"HTTP/2 200\r\ncontent-length: 2\r\n\r\n{}").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers["content-length"], "2");
+        assert_eq!(response.body, b"{}");
+
+        // Empty body
+        assert_eq!(
+            parse_response(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap()
+                .status,
+            204
+        );
+
+        assert!(parse_response(b"garbage").is_none());
+    }
+
+    #[test]
+    fn tag_in_index_replaces_only_the_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_type = "application/vnd.oci.image.manifest.v1+json";
+
+        tag_in_index(dir.path(), "22.04", media_type, "sha256:aaa", 10).unwrap();
+        tag_in_index(dir.path(), "24.04", media_type, "sha256:bbb", 20).unwrap();
+        tag_in_index(dir.path(), "22.04", media_type, "sha256:ccc", 30).unwrap();
+
+        let index: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.path().join("index.json")).unwrap()).unwrap();
+        let manifests = index["manifests"].as_array().unwrap();
+        assert_eq!(manifests.len(), 2);
+        assert_eq!(manifests[0]["digest"], "sha256:bbb");
+        assert_eq!(manifests[1]["digest"], "sha256:ccc");
+        assert_eq!(manifests[1]["size"], 30);
+        assert_eq!(manifests[1]["annotations"][ANNOTATION_REF_NAME], "22.04");
+    }
+}
@@ -0,0 +1,51 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Recipe of a customized rootfs for `wsl-get build`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Template {
+    /// Image to start from (e.g. ubuntu:22.04)
+    pub base: String,
+    /// Packages to install with the package manager of the base image
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// Shell commands to run as root after installing packages
+    #[serde(default)]
+    pub run: Vec<String>,
+}
+
+impl Template {
+    pub fn load(path: &Path) -> Result<Self> {
+        toml::from_str(&fs::read_to_string(path)?)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+}
+
+/// Package managers and the commands to install packages non-interactively
+const PACKAGE_MANAGERS: &[(&str, &str)] = &[
+    (
+        "/usr/bin/apt-get",
+        "apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y",
+    ),
+    ("/usr/bin/dnf", "dnf install -y"),
+    ("/usr/bin/yum", "yum install -y"),
+    ("/sbin/apk", "apk add --no-cache"),
+    ("/usr/bin/pacman", "pacman -Syu --noconfirm"),
+    ("/usr/bin/zypper", "zypper --non-interactive install"),
+];
+
+/// Build the command to install `packages`, given a predicate whether a file exists in the distro
+pub fn install_command(
+    packages: &[String],
+    mut file_exists: impl FnMut(&str) -> Result<bool>,
+) -> Result<Option<String>> {
+    for (path, command) in PACKAGE_MANAGERS {
+        if file_exists(path)? {
+            return Ok(Some(format!("{} {}", command, packages.join(" "))));
+        }
+    }
+    Ok(None)
+}
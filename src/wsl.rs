@@ -1,29 +1,67 @@
 use std::{
     cell::RefCell,
+    collections::HashMap,
     convert::TryInto,
-    ffi::CStr,
-    path::Path,
-    process::{Command, Stdio},
+    ffi::{CStr, OsStr},
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
     ptr::null_mut,
     slice,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use crate::bindings::Windows::Win32::{
-    Foundation::{BOOL, HANDLE, HINSTANCE, PSTR, PWSTR},
+    Foundation::{CloseHandle, BOOL, HANDLE, HINSTANCE, PSTR, PWSTR},
+    Security::SECURITY_ATTRIBUTES,
+    Storage::FileSystem::{
+        GetDiskFreeSpaceExW, GetVolumeInformationW, GetVolumePathNameW, ReadFile,
+    },
     System::{
         Com::CoTaskMemFree,
+        Console::{GetStdHandle, STD_INPUT_HANDLE},
         LibraryLoader::{
             FreeLibrary, GetProcAddress, LoadLibraryExW, LOAD_LIBRARY_SEARCH_SYSTEM32,
         },
+        Pipes::CreatePipe,
+        Registry::{
+            RegCloseKey, RegEnumKeyExW, RegGetValueW, RegOpenKeyExW, HKEY, HKEY_CURRENT_USER,
+            KEY_READ, RRF_RT_REG_SZ,
+        },
         SubsystemForLinux::WSL_DISTRIBUTION_FLAGS,
+        Threading::{GetExitCodeProcess, WaitForSingleObject},
+        WindowsProgramming::INFINITE,
     },
 };
-use anyhow::{bail, Result};
+use crate::{
+    archive::ImportFormat,
+    error::Failure,
+    output,
+    progress::{self, InstallEvent},
+};
+use anyhow::{anyhow, bail, Result};
 use scopeguard::defer;
 use windows::{IntoParam, HRESULT};
 
+const MAX_PATH: usize = 260;
+
+/// Key under HKEY_CURRENT_USER where WSL keeps the registered distributions
+const LXSS_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Lxss";
+const ERROR_FILE_NOT_FOUND: i32 = 2;
+const ERROR_NO_MORE_ITEMS: i32 = 259;
+
+/// Host resolved by the post-install network check
+const NETWORK_CHECK_HOST: &str = "deb.debian.org";
+/// Profile script that runs the command given by `install --default-command`
+const DEFAULT_COMMAND_SCRIPT: &str = "/etc/profile.d/wsl-get-default-command.sh";
+/// Nameservers written to /etc/resolv.conf when the network check fails
+const FALLBACK_NAMESERVERS: &[&str] = &["1.1.1.1", "8.8.8.8"];
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct WSL {
     dll: HINSTANCE,
+    cli: WslCli,
 
     configure_distribution: unsafe extern "system" fn(
         distributionname: PWSTR,
@@ -47,6 +85,16 @@ pub struct WSL {
         exitcode: *mut u32,
     ) -> ::windows::HRESULT,
 
+    launch: unsafe extern "system" fn(
+        distributionname: PWSTR,
+        command: PWSTR,
+        usecurrentworkingdirectory: BOOL,
+        stdin: HANDLE,
+        stdout: HANDLE,
+        stderr: HANDLE,
+        process: *mut HANDLE,
+    ) -> ::windows::HRESULT,
+
     is_distribution_registered: unsafe extern "system" fn(distributionname: PWSTR) -> BOOL,
 
     unregister_distribution:
@@ -65,13 +113,33 @@ pub struct DistributionConfiguration {
     pub default_environment_variables: Vec<String>,
 }
 
+/// User to create on installation
+#[derive(Debug)]
+pub struct NewUser {
+    pub name: String,
+    /// Password for the user. `None` leaves the user without password.
+    pub password: Option<String>,
+    /// Set the password of root to the one of the user as well
+    pub root_password: bool,
+    pub home: Option<String>,
+    /// Full name stored as the GECOS comment
+    pub full_name: Option<String>,
+    pub nopasswd_sudo: bool,
+    /// Skip adding the user to `wheel` and `sudo`
+    pub no_group_add: bool,
+    /// Extra arguments to `useradd`
+    pub useradd_args: Vec<String>,
+}
+
 impl WSL {
+    #[allow(clippy::missing_transmute_annotations)]
     pub fn new() -> Self {
         let dll =
             unsafe { LoadLibraryExW("wslapi.dll", HANDLE::NULL, LOAD_LIBRARY_SEARCH_SYSTEM32) };
 
         Self {
             dll,
+            cli: WslCli::new(Box::new(SystemCommandRunner)),
             configure_distribution: unsafe {
                 std::mem::transmute(GetProcAddress(dll, "WslConfigureDistribution"))
             },
@@ -81,6 +149,7 @@ impl WSL {
             launch_interactive: unsafe {
                 std::mem::transmute(GetProcAddress(dll, "WslLaunchInteractive"))
             },
+            launch: unsafe { std::mem::transmute(GetProcAddress(dll, "WslLaunch")) },
             is_distribution_registered: unsafe {
                 std::mem::transmute(GetProcAddress(dll, "WslIsDistributionRegistered"))
             },
@@ -93,19 +162,8 @@ impl WSL {
         }
     }
 
-    // workaround for missing enumerate API
-    pub fn list_installed_distros(&self) -> Result<Vec<String>> {
-        let output = Command::new("wsl.exe")
-            .arg("--list")
-            .arg("--quiet")
-            .output()?;
-
-        Ok(decode_utf16(&output.stdout)?
-            .lines()
-            .map(|w| w.trim_end().to_string())
-            .collect::<Vec<String>>())
-    }
-
+    /// Note that default environment variables cannot be changed by this API,
+    /// so they are left as is.
     pub fn configure_distribution(
         &self,
         distribution_name: &str,
@@ -122,6 +180,39 @@ impl WSL {
         .ok()?)
     }
 
+    /// Change the default user, keeping the other configuration.
+    /// `WslConfigureDistribution` always sets the flags along with the uid, so the flags read
+    /// just before are written back as they are, and the result is read again to make sure
+    /// that nothing else (e.g. another process changing the flags meanwhile) was lost.
+    pub fn set_default_uid(&self, distribution_name: &str, default_uid: u32) -> Result<()> {
+        let conf = self.get_distribution_configuration(distribution_name)?;
+        let flags = conf.wsl_distribution_flags;
+        self.configure_distribution(distribution_name, default_uid, flags)?;
+
+        let new_conf = self.get_distribution_configuration(distribution_name)?;
+        if new_conf.default_uid != default_uid {
+            bail!(
+                "Default uid of {} is {} instead of {}; it may have been changed at the same time",
+                distribution_name,
+                new_conf.default_uid,
+                default_uid
+            );
+        }
+        if new_conf.wsl_distribution_flags != flags {
+            bail!(
+                "Flags of {} were changed from {:#x} to {:#x} at the same time; check them with `wsl-get config`",
+                distribution_name,
+                flags.0,
+                new_conf.wsl_distribution_flags.0
+            );
+        }
+        if new_conf.default_environment_variables != conf.default_environment_variables {
+            bail!("Default environment variables were changed unexpectedly");
+        }
+
+        Ok(())
+    }
+
     pub fn get_distribution_configuration(
         &self,
         distribution_name: &str,
@@ -192,6 +283,126 @@ impl WSL {
         Ok(exitcode)
     }
 
+    /// Run command as the default user, capturing its stdout and stderr
+    pub fn run_capture(&self, distribution_name: &str, command: &str) -> Result<CapturedOutput> {
+        let (stdout_read, stdout_write) = create_pipe()?;
+        let (stderr_read, stderr_write) = create_pipe()?;
+
+        defer! {
+            unsafe {
+                CloseHandle(stdout_read);
+                CloseHandle(stderr_read);
+            }
+        }
+
+        let mut process = HANDLE::NULL;
+        let res = unsafe {
+            (self.launch)(
+                IntoParam::<PWSTR>::into_param(distribution_name).abi(),
+                IntoParam::<PWSTR>::into_param(command).abi(),
+                IntoParam::<BOOL>::into_param(false).abi(),
+                GetStdHandle(STD_INPUT_HANDLE),
+                stdout_write,
+                stderr_write,
+                &mut process,
+            )
+        };
+
+        // The process has its own copies of the write ends; close ours so that reading ends
+        unsafe {
+            CloseHandle(stdout_write);
+            CloseHandle(stderr_write);
+        }
+
+        res.ok()?;
+
+        defer! {
+            unsafe { CloseHandle(process); }
+        }
+
+        // Read both pipes at the same time, or the process may block on a full pipe
+        let stderr = thread::spawn(move || read_pipe(stderr_read));
+        let stdout = read_pipe(stdout_read);
+        let stderr = stderr.join().unwrap();
+
+        unsafe { WaitForSingleObject(process, INFINITE) };
+
+        let mut exit_code = 0;
+        if !unsafe { GetExitCodeProcess(process, &mut exit_code) }.as_bool() {
+            bail!("Failed to get exit code of `{}`", command);
+        }
+
+        Ok(CapturedOutput {
+            exit_code,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Run command via `wsl.exe` as `user` (or the default user) and return its exit code.
+    /// `env` is exported to the command.
+    pub fn run(
+        &self,
+        distribution_name: &str,
+        user: Option<&str>,
+        env: &[(String, String)],
+        command: &[String],
+    ) -> Result<i32> {
+        let mut cmd = Command::new("wsl.exe");
+        cmd.arg("-d").arg(distribution_name);
+        if let Some(user) = user {
+            cmd.arg("-u").arg(user);
+        }
+        if !env.is_empty() {
+            // Variables listed in WSLENV are passed through as is,
+            // which avoids quoting them for the shell in the distribution.
+            let mut wslenv = std::env::var("WSLENV").unwrap_or_default();
+            for (key, value) in env {
+                if !wslenv.is_empty() {
+                    wslenv.push(':');
+                }
+                wslenv.push_str(key);
+                cmd.env(key, value);
+            }
+            cmd.env("WSLENV", wslenv);
+        }
+        let stat = cmd.arg("--").args(command).status()?;
+
+        stat.code()
+            .ok_or_else(|| anyhow!("Command was terminated abnormally"))
+    }
+
+    /// Run `command` with `wsl.exe -e`, which doesn't pass it through the shell of `user`
+    pub fn exec(
+        &self,
+        distribution_name: &str,
+        user: Option<&str>,
+        command: &[String],
+    ) -> Result<i32> {
+        let mut cmd = Command::new("wsl.exe");
+        cmd.arg("-d").arg(distribution_name);
+        if let Some(user) = user {
+            cmd.arg("-u").arg(user);
+        }
+        let stat = cmd.arg("-e").args(command).status()?;
+
+        stat.code()
+            .ok_or_else(|| anyhow!("Command was terminated abnormally"))
+    }
+
+    /// Open an interactive shell as `user` (or the default user) and return its exit code
+    pub fn shell(&self, distribution_name: &str, user: Option<&str>) -> Result<i32> {
+        let mut cmd = Command::new("wsl.exe");
+        cmd.arg("-d").arg(distribution_name);
+        if let Some(user) = user {
+            cmd.arg("-u").arg(user);
+        }
+        let stat = cmd.status()?;
+
+        stat.code()
+            .ok_or_else(|| anyhow!("Shell was terminated abnormally"))
+    }
+
     pub fn is_distribution_registered(&self, distribution_name: &str) -> bool {
         unsafe {
             (self.is_distribution_registered)(
@@ -213,6 +424,9 @@ impl WSL {
         distribution_name: &str,
         data_dir: &Path,
         targz_filename: &Path,
+        version: u32,
+        timeout: Option<Duration>,
+        retries: u32,
     ) -> Result<()> {
         // Ok(unsafe {
         //     (self.register_distribution)(
@@ -227,32 +441,320 @@ impl WSL {
         // This API is not suitable for this program,
         // so it use `wsl.exe --import` command.
 
+        let import = || {
+            run_import(
+                distribution_name,
+                data_dir,
+                targz_filename.as_os_str(),
+                Stdio::inherit(),
+                version,
+                timeout,
+            )
+        };
+
+        let mut output = import()?;
+
+        for attempt in 1..=retries {
+            if output.status.success() || !is_transient_error(&decode_output(&output)) {
+                break;
+            }
+
+            // The WSL service sometimes gets stuck; restarting it usually helps.
+            eprintln!(
+                "Import failed, retrying after `wsl.exe --shutdown` ({}/{})...",
+                attempt, retries
+            );
+            self.shutdown()?;
+            thread::sleep(Duration::from_secs(3));
+            output = import()?;
+        }
+
+        check_import(&output, data_dir, targz_filename, version)
+    }
+
+    /// Register a distribution from a rootfs tarball written to `stdin`, without a file.
+    /// Unlike `register_distribution`, this is not retried since the stream is consumed.
+    pub fn register_distribution_from_stdin(
+        &self,
+        distribution_name: &str,
+        data_dir: &Path,
+        stdin: Stdio,
+        version: u32,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let output = run_import(
+            distribution_name,
+            data_dir,
+            OsStr::new("-"),
+            stdin,
+            version,
+            timeout,
+        )?;
+        check_import(&output, data_dir, Path::new("-"), version)
+    }
+
+    /// Whether `wsl.exe --import` reads the tarball from stdin when given `-`.
+    /// The inbox WSL, which has no `--version`, doesn't.
+    pub fn supports_import_from_stdin(&self) -> bool {
+        self.version().is_ok_and(|version| {
+            let major = version
+                .split('.')
+                .next()
+                .and_then(|v| v.parse::<u32>().ok());
+            major.is_some_and(|major| major >= 1)
+        })
+    }
+
+    /// Formats of the tarball that `wsl.exe --import` accepts.
+    /// The inbox WSL is assumed to accept only gzip, which wsl-get has always given it.
+    pub fn import_formats(&self) -> Vec<ImportFormat> {
+        if self.version().is_ok() {
+            ImportFormat::ALL.to_vec()
+        } else {
+            vec![ImportFormat::Gzip]
+        }
+    }
+
+    pub fn shutdown(&self) -> Result<()> {
+        let stat = Command::new("wsl.exe")
+            .arg("--shutdown")
+            .stdout(output::child_stdout())
+            .status()?;
+
+        if !stat.success() {
+            bail!("Failed to shutdown WSL");
+        }
+
+        Ok(())
+    }
+
+    pub fn set_default_distribution(&self, distribution_name: &str) -> Result<()> {
+        let stat = Command::new("wsl.exe")
+            .arg("--set-default")
+            .arg(distribution_name)
+            .stdout(Stdio::null())
+            .status()?;
+
+        if !stat.success() {
+            bail!("Failed to set default distribution");
+        }
+
+        Ok(())
+    }
+
+    pub fn terminate_distribution(&self, distribution_name: &str) -> Result<()> {
+        let stat = Command::new("wsl.exe")
+            .arg("--terminate")
+            .arg(distribution_name)
+            .stdout(Stdio::null())
+            .status()?;
+
+        if !stat.success() {
+            bail!("Failed to terminate distribution");
+        }
+
+        Ok(())
+    }
+
+    pub fn export_distribution(&self, distribution_name: &str, filename: &Path) -> Result<()> {
+        let stat = Command::new("wsl.exe")
+            .arg("--export")
+            .arg(distribution_name)
+            .arg(filename)
+            .stdout(output::child_stdout())
+            .status()?;
+
+        if !stat.success() {
+            bail!("Failed to export distribution");
+        }
+
+        Ok(())
+    }
+
+    /// Get the directory that contains the VHD of the distribution from the registry
+    pub fn base_path(&self, distribution_name: &str) -> Result<Option<PathBuf>> {
+        Ok(self.base_paths()?.remove(distribution_name))
+    }
+
+    /// Directories that contain the VHDs of all the registered distributions, by name.
+    /// Read with the registry API, since `reg.exe` prints non-ASCII paths in the OEM codepage.
+    pub fn base_paths(&self) -> Result<HashMap<String, PathBuf>> {
+        let mut lxss = HKEY(0);
+        let status = unsafe { RegOpenKeyExW(HKEY_CURRENT_USER, LXSS_KEY, 0, KEY_READ, &mut lxss) };
+        match status.0 {
+            0 => {}
+            // No distribution has ever been registered
+            ERROR_FILE_NOT_FOUND => return Ok(HashMap::new()),
+            code => bail!("Failed to open the registry key of WSL (error {})", code),
+        }
+
+        defer! {
+            unsafe { RegCloseKey(lxss); }
+        }
+
+        // Each distribution is a subkey named with a GUID, having
+        // `DistributionName` and `BasePath` values
+        let mut ret = HashMap::new();
+        for index in 0.. {
+            let mut subkey = [0u16; 256];
+            let mut len = subkey.len() as u32;
+            let status = unsafe {
+                RegEnumKeyExW(
+                    lxss,
+                    index,
+                    PWSTR(subkey.as_mut_ptr()),
+                    &mut len,
+                    null_mut(),
+                    PWSTR::NULL,
+                    null_mut(),
+                    null_mut(),
+                )
+            };
+            match status.0 {
+                0 => {}
+                ERROR_NO_MORE_ITEMS => break,
+                code => bail!(
+                    "Failed to enumerate distributions in the registry (error {})",
+                    code
+                ),
+            }
+
+            let subkey = String::from_utf16_lossy(&subkey[..len as usize]);
+            let name = reg_get_string(lxss, &subkey, "DistributionName")?;
+            let base_path = reg_get_string(lxss, &subkey, "BasePath")?;
+            if let (Some(name), Some(path)) = (name, base_path) {
+                let path = PathBuf::from(path.strip_prefix(r"\\?\").unwrap_or(&path));
+                ret.insert(name, path);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Version set by `wsl.exe --set-default-version`, if any
+    pub fn default_version(&self) -> Result<Option<u32>> {
+        let output = Command::new("reg.exe")
+            .args([
+                "query",
+                r"HKCU\Software\Microsoft\Windows\CurrentVersion\Lxss",
+                "/v",
+                "DefaultVersion",
+            ])
+            .stderr(Stdio::null())
+            .output()?;
+
+        // Fails if the value doesn't exist
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        //     DefaultVersion    REG_DWORD    0x2
+        let output = String::from_utf8_lossy(&output.stdout);
+        Ok(output
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("DefaultVersion"))
+            .filter_map(|rest| rest.split_whitespace().nth(1))
+            .find_map(|value| u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()))
+    }
+
+    pub fn import_in_place(&self, distribution_name: &str, vhdx_filename: &Path) -> Result<()> {
         let stat = Command::new("wsl.exe")
-            .arg("--import")
+            .arg("--import-in-place")
             .arg(distribution_name)
-            .arg(data_dir)
-            .arg(targz_filename)
-            .args(&["--version", "2"])
+            .arg(vhdx_filename)
+            .stdout(output::child_stdout())
             .status()?;
 
         if !stat.success() {
-            bail!("Failed to register distribution");
+            bail!("Failed to import distribution in place");
         }
 
         Ok(())
     }
 
-    pub fn create_user(&self, distro_name: &str, user_name: &str, password: &str) -> Result<()> {
+    pub fn create_user(&self, distro_name: &str, user: &NewUser) -> Result<()> {
+        let user_name = user.name.as_str();
         let bash_path = self.lookup_shell(distro_name)?;
 
+        // Find all the tools before changing anything
+        let useradd = self.require_tool(distro_name, "useradd")?;
+        let userdel = self.require_tool(distro_name, "userdel")?;
+        let chpasswd = match &user.password {
+            Some(_) => Some(self.require_tool(distro_name, "chpasswd")?),
+            None => None,
+        };
+        let usermod = match user.no_group_add {
+            false => Some(self.require_tool(distro_name, "usermod")?),
+            true => None,
+        };
+        let visudo = match user.nopasswd_sudo {
+            true => Some(
+                self.cli
+                    .lookup_tool(distro_name, "visudo")?
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "visudo is not found in {}. Install sudo and try again",
+                            distro_name
+                        )
+                    })?,
+            ),
+            false => None,
+        };
+
+        // Options given by the user take precedence over ones set by us
+        let has_option = |short: &str, long: &str| has_option(&user.useradd_args, short, long);
+
         let mut user_add_args = vec![];
-        user_add_args.push("/usr/sbin/useradd".to_owned());
+        user_add_args.push(useradd);
         if let Some(bash_path) = bash_path {
-            user_add_args.push("-s".to_owned());
-            user_add_args.push(bash_path);
+            if has_option("-s", "--shell") {
+                eprintln!(
+                    "Warning: the shell given by --useradd-arg overrides {}",
+                    bash_path
+                );
+            } else {
+                user_add_args.push("-s".to_owned());
+                user_add_args.push(bash_path);
+            }
+        }
+        if let Some(home) = user
+            .home
+            .as_ref()
+            .filter(|_| !has_option("-d", "--home-dir"))
+        {
+            // useradd -m does not create parent directories of the home
+            let ec = self.launch_interactive(
+                distro_name,
+                &format!("mkdir -p \"$(dirname {})\"", shell_quote(home)),
+                true,
+            )?;
+            if ec != 0 {
+                bail!("Failed to create parent directory of {}.", home);
+            }
+
+            user_add_args.push("-d".to_owned());
+            user_add_args.push(shell_quote(home));
+        }
+        if let Some(full_name) = &user.full_name {
+            if has_option("-c", "--comment") {
+                eprintln!("Warning: the comment given by --useradd-arg overrides --full-name");
+            } else {
+                user_add_args.push("-c".to_owned());
+                user_add_args.push(shell_quote(full_name));
+            }
+        }
+        if user.home.is_some() && has_option("-d", "--home-dir") {
+            eprintln!("Warning: the home directory given by --useradd-arg overrides --user-home");
+        }
+        if has_option("-m", "--create-home") {
+            eprintln!("Warning: -m is already passed to useradd");
+        } else if has_option("-M", "--no-create-home") {
+            eprintln!("Warning: the home directory will not be created");
+        } else {
+            user_add_args.push("-m".to_owned());
         }
-        user_add_args.push("-m".to_owned());
-        user_add_args.push(user_name.to_owned());
+        user_add_args.extend(user.useradd_args.iter().map(|arg| shell_quote(arg)));
+        user_add_args.push(shell_quote(user_name));
 
         let ec = self.launch_interactive(distro_name, &user_add_args.join(" "), true)?;
         if ec != 0 {
@@ -265,16 +767,47 @@ impl WSL {
             if !*complete.borrow() {
                 self.launch_interactive(
                     distro_name,
-                    &format!("/usr/sbin/userdel --remove {}", user_name),
+                    &format!("{} --remove {}", userdel, shell_quote(user_name)),
                     true,
                 ).unwrap();
             }
         }
 
+        // Some minimal images lack /home or /etc/skel, and useradd -m silently skips the home
+        if !has_option("-M", "--no-create-home") && !has_option("-d", "--home-dir") {
+            let home = user
+                .home
+                .clone()
+                .unwrap_or_else(|| format!("/home/{}", user_name));
+
+            if !self.file_exists(distro_name, &home)? {
+                eprintln!(
+                    "Warning: home directory {} was not created by useradd. Creating it.",
+                    home
+                );
+                let ec = self.launch_interactive(
+                    distro_name,
+                    &format!(
+                        "mkdir -p {0} && chown {1}: {0} && chmod 755 {0}",
+                        shell_quote(&home),
+                        shell_quote(user_name)
+                    ),
+                    true,
+                )?;
+                if ec != 0 {
+                    bail!("Failed to create home directory {}.", home);
+                }
+            }
+        }
+
         let change_password = |user, pass| {
             let ec = self.launch_interactive(
                 distro_name,
-                &format!("echo {}:{} | /usr/sbin/chpasswd", user, pass),
+                &format!(
+                    "printf '%s\\n' {} | {}",
+                    shell_quote(&format!("{}:{}", user, pass)),
+                    chpasswd.as_deref().unwrap()
+                ),
                 true,
             )?;
             if ec != 0 {
@@ -283,73 +816,1310 @@ impl WSL {
             Ok(())
         };
 
-        change_password("root", password)?;
-        change_password(user_name, password)?;
+        match &user.password {
+            Some(password) => {
+                if user.root_password {
+                    change_password("root", password)?;
+                }
+                change_password(user_name, password)?;
+            }
+            None => {
+                // Empty password instead of locked one, so that the user can log in
+                let ec = self.launch_interactive(
+                    distro_name,
+                    &format!("passwd -d {} > /dev/null", shell_quote(user_name)),
+                    true,
+                )?;
+                if ec != 0 {
+                    bail!("Failed to delete password.");
+                }
+            }
+        }
+
+        if let Some(usermod) = &usermod {
+            let add_group_if_exists = |group: &str| {
+                self.launch_interactive(
+                    distro_name,
+                    &format!(
+                        "getent group {} > /dev/null && {} -aG {} {}",
+                        group,
+                        usermod,
+                        group,
+                        shell_quote(user_name)
+                    ),
+                    true,
+                )
+            };
+
+            add_group_if_exists("wheel")?;
+            add_group_if_exists("sudo")?;
+        }
 
-        let add_group_if_exists = |group: &str| {
-            self.launch_interactive(
+        if let Some(visudo) = &visudo {
+            // Validate before installing, since a broken sudoers file disables sudo entirely
+            let ec = self.launch_interactive(
                 distro_name,
                 &format!(
-                    "getent group {} > /dev/null && /usr/sbin/usermod -aG {} {}",
-                    group, group, user_name
+                    "mkdir -p /etc/sudoers.d && tmp=$(mktemp) && \
+                     printf '%s\\n' {0} > \"$tmp\" && {1} -cqf \"$tmp\" && \
+                     install -m 440 \"$tmp\" {2}; ec=$?; rm -f \"$tmp\"; exit $ec",
+                    shell_quote(&format!("{} ALL=(ALL) NOPASSWD:ALL", user_name)),
+                    visudo,
+                    sudoers_file(user_name)
                 ),
                 true,
-            )
-        };
-
-        add_group_if_exists("wheel")?;
-        add_group_if_exists("sudo")?;
+            )?;
+            if ec != 0 {
+                bail!("Failed to allow sudo without password.");
+            }
+        }
 
         *complete.borrow_mut() = true;
 
         Ok(())
     }
 
-    pub fn file_exists(&self, distro_name: &str, file: &str) -> Result<bool> {
-        let ec =
-            self.launch_interactive(distro_name, &format!("/usr/bin/test -e {}", file), true)?;
-        Ok(ec == 0)
+    /// Remove a user created by `create_user`, with its home and sudoers entry
+    pub fn remove_user(&self, distro_name: &str, user_name: &str) -> Result<()> {
+        let userdel = self.require_tool(distro_name, "userdel")?;
+        let ec = self.launch_interactive(
+            distro_name,
+            &format!(
+                "{} --remove {} && rm -f {}",
+                userdel,
+                shell_quote(user_name),
+                sudoers_file(user_name)
+            ),
+            true,
+        )?;
+        if ec != 0 {
+            bail!("Failed to remove user {}.", user_name);
+        }
+        Ok(())
     }
 
-    pub fn lookup_shell(&self, distro_name: &str) -> Result<Option<String>> {
-        let shells = &["/usr/bin/bash", "/bin/bash", "/usr/bin/sh", "/bin/sh"];
+    pub fn enable_systemd(&self, distro_name: &str) -> Result<()> {
+        let ec = self.launch_interactive(
+            distro_name,
+            // Replace the key in an existing [boot] section, which may have `systemd=false`
+            "if grep -qs '^\\[boot\\]' /etc/wsl.conf; \
+             then sed -i '/^systemd *=/d; /^\\[boot\\]/a systemd=true' /etc/wsl.conf; \
+             else printf '[boot]\\nsystemd=true\\n' >> /etc/wsl.conf; fi",
+            true,
+        )?;
+        if ec != 0 {
+            bail!("Failed to enable systemd.");
+        }
+        Ok(())
+    }
 
-        for &cand in shells {
-            if self.file_exists(distro_name, cand)? {
-                return Ok(Some(cand.to_string()));
-            }
+    /// Set the hostname in the `[network]` section of /etc/wsl.conf, replacing the existing one
+    pub fn set_hostname(&self, distro_name: &str, hostname: &str) -> Result<()> {
+        let ec = self.launch_interactive(
+            distro_name,
+            &format!(
+                "if grep -qs '^\\[network\\]' /etc/wsl.conf; \
+                 then sed -i '/^hostname *=/d; /^\\[network\\]/a hostname={0}' /etc/wsl.conf; \
+                 else printf '[network]\\nhostname={0}\\n' >> /etc/wsl.conf; fi",
+                hostname
+            ),
+            true,
+        )?;
+        if ec != 0 {
+            bail!("Failed to set hostname.");
         }
-        Ok(None)
+        Ok(())
     }
 
-    pub fn query_uid(&self, distro_name: &str, user_name: &str) -> Result<u64> {
-        let output = Command::new("wsl.exe")
-            .arg("-d")
-            .arg(distro_name)
-            .arg("--")
-            .args(&["/usr/bin/id", "-u", &user_name])
-            .stderr(Stdio::piped())
-            .output()?;
+    /// State of systemd reported by `systemctl is-system-running` (e.g. `starting` or `running`)
+    pub fn systemd_state(&self, distro_name: &str) -> Result<String> {
+        // Exits with non-zero unless running, but prints the state anyway
+        let output = self.run_capture(distro_name, "systemctl is-system-running 2>/dev/null")?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
 
-        if !output.status.success() {
-            bail!("Failed to get uid");
+    /// Make login shells run `command` instead of an interactive shell.
+    /// Commands given to `wsl.exe` are run without a login shell, so they are not affected.
+    pub fn set_default_command(&self, distro_name: &str, command: &str) -> Result<()> {
+        let script = format!(
+            "# Written by wsl-get install --default-command\n\
+             case $- in *i*) ;; *) return ;; esac\n\
+             if [ -z \"$WSL_GET_DEFAULT_COMMAND\" ]; then\n\
+             \x20   export WSL_GET_DEFAULT_COMMAND=1\n\
+             \x20   exec /bin/sh -c {}\n\
+             fi\n",
+            shell_quote(command)
+        );
+        let ec = self.launch_interactive(
+            distro_name,
+            &format!(
+                "mkdir -p /etc/profile.d && printf '%s' {} > {}",
+                shell_quote(&script),
+                DEFAULT_COMMAND_SCRIPT
+            ),
+            true,
+        )?;
+        if ec != 0 {
+            bail!("Failed to set the default command.");
         }
+        Ok(())
+    }
 
-        Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+    /// Check that names can be resolved in the distribution
+    pub fn check_network(&self, distro_name: &str) -> Result<bool> {
+        let output = self.run_capture(distro_name, &network_check_command())?;
+        Ok(output.exit_code == 0)
     }
-}
 
-impl Drop for WSL {
-    fn drop(&mut self) {
-        let _ = unsafe { FreeLibrary(self.dll) };
+    /// Run `command` repeatedly until it exits with 0, sleeping `interval` between attempts.
+    /// Returns false if it doesn't succeed within `timeout`. The output of `command` is discarded.
+    pub fn wait_until(
+        &self,
+        distro_name: &str,
+        command: &str,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<bool> {
+        poll_until(timeout, interval, || {
+            let ec = self.launch_interactive(
+                distro_name,
+                &format!("({}) >/dev/null 2>&1", command),
+                true,
+            )?;
+            Ok(ec == 0)
+        })
     }
-}
 
-fn decode_utf16(bytes: &[u8]) -> Result<String> {
-    let output = bytes
-        .chunks_exact(2)
-        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
-        .collect::<Vec<_>>();
+    /// Wait until names can be resolved in the distribution
+    pub fn wait_for_network(
+        &self,
+        distro_name: &str,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<bool> {
+        self.wait_until(distro_name, &network_check_command(), timeout, interval)
+    }
 
-    Ok(String::from_utf16(&output)?)
+    /// Wait until systemd has finished booting. `degraded` means that some units failed,
+    /// but the boot has finished as well.
+    pub fn wait_for_systemd(
+        &self,
+        distro_name: &str,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<bool> {
+        self.wait_until(
+            distro_name,
+            "systemctl is-system-running | grep -qxE 'running|degraded'",
+            timeout,
+            interval,
+        )
+    }
+
+    /// Write a static /etc/resolv.conf and stop WSL from regenerating it
+    pub fn fix_network(&self, distro_name: &str) -> Result<()> {
+        let nameservers = FALLBACK_NAMESERVERS
+            .iter()
+            .map(|ns| format!("nameserver {}\\n", ns))
+            .collect::<String>();
+        let ec = self.launch_interactive(
+            distro_name,
+            &format!(
+                "sed -i '/^generateResolvConf *=/d' /etc/wsl.conf 2>/dev/null; \
+                 if grep -qs '^\\[network\\]' /etc/wsl.conf; \
+                 then sed -i '/^\\[network\\]/a generateResolvConf=false' /etc/wsl.conf; \
+                 else printf '[network]\\ngenerateResolvConf=false\\n' >> /etc/wsl.conf; fi && \
+                 rm -f /etc/resolv.conf && printf '{}' > /etc/resolv.conf",
+                nameservers
+            ),
+            true,
+        )?;
+        if ec != 0 {
+            bail!("Failed to fix network settings.");
+        }
+        Ok(())
+    }
+
+    pub fn list_installed_distros(&self) -> Result<Vec<String>> {
+        self.cli.list_installed_distros()
+    }
+
+    pub fn list_running_distros(&self) -> Result<Vec<String>> {
+        self.cli.list_running_distros()
+    }
+
+    pub fn list_verbose(&self) -> Result<Vec<DistroListEntry>> {
+        self.cli.list_verbose()
+    }
+
+    pub fn find_case_conflict(&self, name: &str) -> Result<Option<String>> {
+        self.cli.find_case_conflict(name)
+    }
+
+    pub fn version(&self) -> Result<String> {
+        self.cli.version()
+    }
+
+    pub fn status(&self) -> Result<WslStatus> {
+        self.cli.status()
+    }
+
+    pub fn service_state(&self, service: &str) -> Result<Option<String>> {
+        self.cli.service_state(service)
+    }
+
+    pub fn file_exists(&self, distro_name: &str, file: &str) -> Result<bool> {
+        self.cli.file_exists(distro_name, file)
+    }
+
+    pub fn is_systemd_image(&self, distro_name: &str) -> Result<bool> {
+        self.cli.is_systemd_image(distro_name)
+    }
+
+    pub fn lookup_shell(&self, distro_name: &str) -> Result<Option<String>> {
+        self.cli.lookup_shell(distro_name)
+    }
+
+    /// Like `lookup_tool`, but fails if the tool is not found
+    fn require_tool(&self, distro_name: &str, tool: &str) -> Result<String> {
+        self.cli.lookup_tool(distro_name, tool)?.ok_or_else(|| {
+            anyhow!(
+                "{} is not found in {} (looked in {}). Install it (e.g. the `shadow` package) and try again",
+                tool,
+                distro_name,
+                TOOL_DIRS.join(", ")
+            )
+        })
+    }
+
+    pub fn query_uid(&self, distro_name: &str, user_name: &str) -> Result<u64> {
+        let output = self.run_capture(
+            distro_name,
+            &format!("/usr/bin/id -u {}", shell_quote(user_name)),
+        )?;
+        parse_uid(output)
+    }
+}
+
+impl Drop for WSL {
+    fn drop(&mut self) {
+        let _ = unsafe { FreeLibrary(self.dll) };
+    }
+}
+
+/// Runs external programs and collects their output.
+/// Implement this to exercise `WslCli` without Windows.
+pub trait CommandRunner {
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<Output>;
+}
+
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+    }
+}
+
+/// Summary of `wsl.exe --status`
+#[derive(Debug, Default)]
+pub struct WslStatus {
+    pub default_distribution: Option<String>,
+    pub default_version: Option<u32>,
+}
+
+/// Row of `wsl.exe --list --verbose`
+#[derive(Debug)]
+pub struct DistroListEntry {
+    pub name: String,
+    /// As shown by WSL, which may be localized (e.g. `Running`)
+    pub state: String,
+    pub version: Option<u32>,
+    pub is_default: bool,
+}
+
+/// Operations implemented by parsing the output of `wsl.exe`,
+/// which do not need `wslapi.dll`
+pub struct WslCli {
+    runner: Box<dyn CommandRunner>,
+}
+
+impl WslCli {
+    pub fn new(runner: Box<dyn CommandRunner>) -> Self {
+        Self { runner }
+    }
+
+    fn wsl(&self, args: &[&str]) -> Result<Output> {
+        Ok(self.runner.output("wsl.exe", args)?)
+    }
+
+    // workaround for missing enumerate API
+    pub fn list_installed_distros(&self) -> Result<Vec<String>> {
+        let output = self.wsl(&["--list", "--quiet"])?;
+
+        Ok(decode_utf16(&output.stdout)?
+            .lines()
+            .map(|w| w.trim_end().to_string())
+            .collect::<Vec<String>>())
+    }
+
+    /// Find an installed distribution whose name differs from `name` only in casing.
+    /// WSL compares names case-insensitively, unlike `WslIsDistributionRegistered`.
+    pub fn find_case_conflict(&self, name: &str) -> Result<Option<String>> {
+        let lower = name.to_lowercase();
+        Ok(self
+            .list_installed_distros()?
+            .into_iter()
+            .find(|distro| distro != name && distro.to_lowercase() == lower))
+    }
+
+    pub fn list_running_distros(&self) -> Result<Vec<String>> {
+        let output = self.wsl(&["--list", "--running", "--quiet"])?;
+
+        // Fails with a message instead of an empty list when nothing is running
+        if !output.status.success() {
+            return Ok(vec![]);
+        }
+
+        Ok(decode_utf16(&output.stdout)?
+            .lines()
+            .map(|w| w.trim_end().to_string())
+            .filter(|w| !w.is_empty())
+            .collect())
+    }
+
+    /// Parse `wsl.exe --list --verbose`, whose first line is a (localized) header
+    /// and the default distribution is marked with `*`
+    pub fn list_verbose(&self) -> Result<Vec<DistroListEntry>> {
+        let output = self.wsl(&["--list", "--verbose"])?;
+
+        // Fails with a message instead of an empty list when nothing is installed
+        if !output.status.success() {
+            return Ok(vec![]);
+        }
+
+        let output = decode_utf16(&output.stdout)?;
+        let mut lines = output.lines().filter(|line| !line.trim().is_empty());
+        let columns = match lines.next() {
+            Some(header) => header_columns(header),
+            None => return Ok(vec![]),
+        };
+
+        Ok(lines
+            .filter_map(|line| {
+                let line = line.chars().collect::<Vec<_>>();
+                let is_default = line.iter().take(columns[0]).any(|&c| c == '*');
+
+                // Names may contain spaces, so the fields are cut at the columns of the header
+                let field = |i: usize| {
+                    let start = columns[i].min(line.len());
+                    let end = columns
+                        .get(i + 1)
+                        .map_or(line.len(), |&end| end.min(line.len()));
+                    line[start..end]
+                        .iter()
+                        .collect::<String>()
+                        .trim()
+                        .to_string()
+                };
+
+                let name = field(0);
+                if name.is_empty() {
+                    return None;
+                }
+                Some(DistroListEntry {
+                    name,
+                    state: field(1),
+                    version: field(2).parse().ok(),
+                    is_default,
+                })
+            })
+            .collect())
+    }
+
+    pub fn version(&self) -> Result<String> {
+        let output = self.wsl(&["--version"])?;
+
+        if !output.status.success() {
+            bail!("Failed to get WSL version");
+        }
+
+        // First line looks like `WSL version: 1.2.5.0`
+        let output = decode_utf16(&output.stdout)?;
+        let line = output.lines().next().unwrap_or_default();
+        Ok(line
+            .rsplit(':')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string())
+    }
+
+    /// Parse `wsl.exe --status`. Its labels are localized, so only English ones are recognized.
+    pub fn status(&self) -> Result<WslStatus> {
+        let output = self.wsl(&["--status"])?;
+
+        if !output.status.success() {
+            bail!("Failed to get WSL status");
+        }
+
+        // Lines look like `Default Distribution: Ubuntu` and `Default Version: 2`
+        let output = decode_utf16(&output.stdout)?;
+        let mut status = WslStatus::default();
+        for line in output.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "Default Distribution" => status.default_distribution = Some(value.to_string()),
+                "Default Version" => status.default_version = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Get the state (e.g. `RUNNING`) of a Windows service, or None if it doesn't exist
+    pub fn service_state(&self, service: &str) -> Result<Option<String>> {
+        let output = self.runner.output("sc.exe", &["query", service])?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        // `        STATE              : 4  RUNNING`
+        let output = String::from_utf8_lossy(&output.stdout);
+        Ok(output
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("STATE"))
+            .filter_map(|rest| rest.split_whitespace().nth(2))
+            .map(|state| state.to_string())
+            .next())
+    }
+
+    pub fn file_exists(&self, distro_name: &str, file: &str) -> Result<bool> {
+        let output = self.wsl(&["-d", distro_name, "--", "/usr/bin/test", "-e", file])?;
+        Ok(output.status.success())
+    }
+
+    pub fn is_systemd_image(&self, distro_name: &str) -> Result<bool> {
+        self.file_exists(distro_name, "/lib/systemd/systemd")
+    }
+
+    pub fn lookup_shell(&self, distro_name: &str) -> Result<Option<String>> {
+        let shells = &["/usr/bin/bash", "/bin/bash", "/usr/bin/sh", "/bin/sh"];
+
+        for &cand in shells {
+            if self.file_exists(distro_name, cand)? {
+                return Ok(Some(cand.to_string()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find a system tool such as `useradd`, which images put in different directories
+    pub fn lookup_tool(&self, distro_name: &str, tool: &str) -> Result<Option<String>> {
+        for dir in TOOL_DIRS {
+            let cand = format!("{}/{}", dir, tool);
+            if self.file_exists(distro_name, &cand)? {
+                return Ok(Some(cand));
+            }
+        }
+        Ok(None)
+    }
+}
+
+const TOOL_DIRS: &[&str] = &["/usr/sbin", "/sbin", "/usr/bin", "/bin"];
+
+/// File in /etc/sudoers.d for `user_name`. sudo skips files whose names contain `.` or end
+/// with `~`, so characters other than letters, digits, `_` and `-` are replaced with `_`.
+pub fn sudoers_file(user_name: &str) -> String {
+    let name = user_name
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    format!("/etc/sudoers.d/{}", name)
+}
+
+/// Read a string value of `subkey` under `key`, or `None` if it doesn't exist
+fn reg_get_string(key: HKEY, subkey: &str, value: &str) -> Result<Option<String>> {
+    let get = |data: *mut u16, size: &mut u32| unsafe {
+        RegGetValueW(
+            key,
+            subkey,
+            value,
+            RRF_RT_REG_SZ,
+            null_mut(),
+            data as _,
+            size,
+        )
+    };
+
+    let mut size = 0;
+    match get(null_mut(), &mut size).0 {
+        0 => {}
+        ERROR_FILE_NOT_FOUND => return Ok(None),
+        code => bail!(
+            "Failed to read {} from the registry (error {})",
+            value,
+            code
+        ),
+    }
+
+    let mut data = vec![0u16; (size as usize).div_ceil(2)];
+    match get(data.as_mut_ptr(), &mut size).0 {
+        0 => {}
+        code => bail!(
+            "Failed to read {} from the registry (error {})",
+            value,
+            code
+        ),
+    }
+
+    // The size includes the terminating null
+    data.truncate(size as usize / 2);
+    while data.last() == Some(&0) {
+        data.pop();
+    }
+    Ok(Some(String::from_utf16_lossy(&data)))
+}
+
+/// Start positions (in chars) of the name, state and version columns of `wsl.exe --list --verbose`.
+/// Labels are separated by two or more spaces, since localized ones may contain a space.
+fn header_columns(header: &str) -> [usize; 3] {
+    let chars = header.trim_end().chars().collect::<Vec<_>>();
+    let mut starts = vec![];
+    let mut spaces = 2;
+    for (i, &c) in chars.iter().enumerate() {
+        if c == ' ' {
+            spaces += 1;
+            continue;
+        }
+        if spaces >= 2 {
+            starts.push(i);
+        }
+        spaces = 0;
+    }
+
+    match starts[..] {
+        [name, state, .., version] => [name, state, version],
+        // Unexpected header; guess from the usual layout
+        _ => [2, 2 + (chars.len().saturating_sub(2)) / 2, chars.len()],
+    }
+}
+
+/// Output of a command run by `WSL::run_capture`. Unlike `wsl.exe`, it is not re-encoded.
+pub struct CapturedOutput {
+    pub exit_code: u32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Call `check` until it returns true, sleeping `interval` between calls.
+/// Returns false without sleeping further once the next call would be after `timeout`.
+fn poll_until(
+    timeout: Duration,
+    interval: Duration,
+    mut check: impl FnMut() -> Result<bool>,
+) -> Result<bool> {
+    let start = Instant::now();
+    loop {
+        if check()? {
+            return Ok(true);
+        }
+        if start.elapsed() + interval > timeout {
+            return Ok(false);
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Get the uid from the output of `id -u`
+fn parse_uid(output: CapturedOutput) -> Result<u64> {
+    if output.exit_code != 0 {
+        bail!(
+            "Failed to get uid\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+}
+
+fn create_pipe() -> Result<(HANDLE, HANDLE)> {
+    let mut attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: null_mut(),
+        bInheritHandle: true.into(),
+    };
+
+    let mut read = HANDLE::NULL;
+    let mut write = HANDLE::NULL;
+    if !unsafe { CreatePipe(&mut read, &mut write, &mut attributes, 0) }.as_bool() {
+        bail!("Failed to create pipe");
+    }
+
+    Ok((read, write))
+}
+
+/// Read the pipe until the other end is closed
+fn read_pipe(pipe: HANDLE) -> Vec<u8> {
+    let mut ret = vec![];
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let mut read = 0;
+        let ok = unsafe {
+            ReadFile(
+                pipe,
+                buf.as_mut_ptr() as _,
+                buf.len() as u32,
+                &mut read,
+                null_mut(),
+            )
+        };
+        // ReadFile fails with ERROR_BROKEN_PIPE at the end
+        if !ok.as_bool() || read == 0 {
+            break;
+        }
+        ret.extend_from_slice(&buf[..read as usize]);
+    }
+
+    ret
+}
+
+/// Decode stdout and stderr of `wsl.exe`, which may be UTF-16 or UTF-8
+fn decode_output(output: &Output) -> String {
+    let decode = |bytes: &[u8]| {
+        if bytes.len().is_multiple_of(2) && bytes.iter().skip(1).step_by(2).all(|&b| b == 0) {
+            decode_utf16(bytes).unwrap_or_else(|_| String::from_utf8_lossy(bytes).to_string())
+        } else {
+            String::from_utf8_lossy(bytes).to_string()
+        }
+    };
+
+    let mut ret = decode(&output.stdout).trim().to_string();
+    let stderr = decode(&output.stderr);
+    if !stderr.trim().is_empty() {
+        if !ret.is_empty() {
+            ret.push('\n');
+        }
+        ret.push_str(stderr.trim());
+    }
+    ret
+}
+
+/// Get the name of the file system (e.g. `NTFS`) of the volume containing `path`
+pub fn file_system_name(path: &Path) -> Result<String> {
+    let path = path.to_string_lossy();
+
+    let mut volume = vec![0u16; MAX_PATH];
+    let ok = unsafe {
+        GetVolumePathNameW(
+            path.as_ref(),
+            PWSTR(volume.as_mut_ptr()),
+            volume.len() as u32,
+        )
+    };
+    if !ok.as_bool() {
+        bail!("Failed to get the volume of {}", path);
+    }
+
+    let mut name = vec![0u16; MAX_PATH + 1];
+    let ok = unsafe {
+        GetVolumeInformationW(
+            PWSTR(volume.as_mut_ptr()),
+            PWSTR(null_mut()),
+            0,
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            PWSTR(name.as_mut_ptr()),
+            name.len() as u32,
+        )
+    };
+    if !ok.as_bool() {
+        bail!("Failed to get the volume information of {}", path);
+    }
+
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Ok(String::from_utf16_lossy(&name[..len]))
+}
+
+/// Attach the VHD to the WSL2 VM and mount it read-only at /mnt/wsl/<name> in all distributions.
+/// This requires administrator privileges.
+pub fn mount_vhd(vhdx: &Path, name: &str) -> Result<()> {
+    let output = Command::new("wsl.exe")
+        .arg("--mount")
+        .arg("--vhd")
+        .arg(vhdx)
+        .args(["--name", name, "--options", "ro"])
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "Failed to mount {}\n{}",
+            vhdx.display(),
+            decode_output(&output)
+        );
+    }
+
+    Ok(())
+}
+
+/// Detach the VHD attached by `mount_vhd`
+pub fn unmount_vhd(vhdx: &Path) -> Result<()> {
+    let output = Command::new("wsl.exe")
+        .arg("--unmount")
+        .arg(vhdx)
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "Failed to unmount {}\n{}",
+            vhdx.display(),
+            decode_output(&output)
+        );
+    }
+
+    Ok(())
+}
+
+/// Get the free space available to the current user on the volume containing `path`
+pub fn free_space(path: &Path) -> Result<u64> {
+    let mut free = 0;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            path.to_string_lossy().as_ref(),
+            &mut free,
+            null_mut(),
+            null_mut(),
+        )
+    };
+    if !ok.as_bool() {
+        bail!("Failed to get free space of {}", path.display());
+    }
+    Ok(free)
+}
+
+/// Check if `args` contains the option, as `-s`, `-svalue`, `--shell` or `--shell=value`
+fn has_option(args: &[String], short: &str, long: &str) -> bool {
+    args.iter().any(|arg| {
+        arg.starts_with(short) && !arg.starts_with("--")
+            || arg == long
+            || arg.starts_with(&format!("{}=", long))
+    })
+}
+
+/// Quote a string for the shell in the distribution
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Run `wsl.exe --import` reading the tarball `file` (`-` for `stdin`), showing the progress
+fn run_import(
+    distribution_name: &str,
+    data_dir: &Path,
+    file: &OsStr,
+    stdin: Stdio,
+    version: u32,
+    timeout: Option<Duration>,
+) -> Result<Output> {
+    let mut child = Command::new("wsl.exe")
+        .arg("--import")
+        .arg(distribution_name)
+        .arg(data_dir)
+        .arg(file)
+        .arg("--version")
+        .arg(version.to_string())
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read the output on threads, since wsl.exe blocks once a pipe is full
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let start = Instant::now();
+    let vhdx = data_dir.join("ext4.vhdx");
+    let mut last_report: Option<(Instant, Option<u64>)> = None;
+
+    // The size of VHD gives a rough idea of the progress
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+            progress::emit(InstallEvent::ImportTimedOut);
+            let _ = child.kill();
+            bail!("Timed out while importing distribution");
+        }
+
+        // At most once a second, and only when the size changes, not to flood `--progress json`
+        let bytes = fs::metadata(&vhdx).ok().map(|meta| meta.len());
+        let due = last_report.is_none_or(|(time, last_bytes)| {
+            time.elapsed() >= Duration::from_secs(1) && last_bytes != bytes
+        });
+        if due {
+            progress::emit(InstallEvent::Importing { bytes });
+            last_report = Some((Instant::now(), bytes));
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    progress::emit(InstallEvent::Imported);
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn read_in_background<R: io::Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Turn the failure of `wsl.exe --import` into an error with hints
+fn check_import(output: &Output, data_dir: &Path, file: &Path, version: u32) -> Result<()> {
+    if !output.status.success() {
+        let message = decode_output(output);
+
+        if version == 2
+            && (message.contains("The supplied version is not supported")
+                || message.contains("WSL_E_VM_MODE"))
+        {
+            bail!(Failure::WslUnavailable(format!(
+                "Failed to register distribution: WSL2 is not available.\n\
+                 Make sure the Virtual Machine Platform feature is enabled and run `wsl.exe --update`.\n{}",
+                message
+            )));
+        }
+
+        if message.contains("cannot find") || message.contains("not found") {
+            bail!(
+                "Failed to register distribution: a file was not found.\n\
+                 Check that `{}` and `{}` are accessible.\n{}",
+                data_dir.display(),
+                file.display(),
+                message
+            );
+        }
+
+        bail!("Failed to register distribution\n{}", message);
+    }
+
+    Ok(())
+}
+
+fn network_check_command() -> String {
+    format!(
+        "getent hosts {0} >/dev/null 2>&1 || ping -c 1 -W 5 {0} >/dev/null 2>&1",
+        NETWORK_CHECK_HOST
+    )
+}
+
+fn is_transient_error(message: &str) -> bool {
+    ["timed out", "RPC", "service", "E_UNEXPECTED"]
+        .iter()
+        .any(|pat| message.contains(pat))
+}
+
+fn decode_utf16(bytes: &[u8]) -> Result<String> {
+    let output = bytes
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+        .collect::<Vec<_>>();
+
+    Ok(String::from_utf16(&output)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, process::ExitStatus, rc::Rc};
+
+    use super::*;
+
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> ExitStatus {
+        std::os::unix::process::ExitStatusExt::from_raw(code << 8)
+    }
+
+    #[cfg(windows)]
+    fn exit_status(code: i32) -> ExitStatus {
+        std::os::windows::process::ExitStatusExt::from_raw(code as u32)
+    }
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+    }
+
+    /// Programs and arguments given to `MockRunner`
+    type Calls = Rc<RefCell<Vec<(String, Vec<String>)>>>;
+
+    /// Answers with canned outputs keyed by the arguments, and records the calls
+    #[derive(Default)]
+    struct MockRunner {
+        outputs: HashMap<Vec<String>, (i32, Vec<u8>)>,
+        calls: Calls,
+    }
+
+    impl MockRunner {
+        fn with(mut self, args: &[&str], code: i32, stdout: Vec<u8>) -> Self {
+            let args = args.iter().map(|arg| arg.to_string()).collect();
+            self.outputs.insert(args, (code, stdout));
+            self
+        }
+
+        fn into_cli(self) -> (WslCli, Calls) {
+            let calls = self.calls.clone();
+            (WslCli::new(Box::new(self)), calls)
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn output(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+            let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            self.calls
+                .borrow_mut()
+                .push((program.to_string(), args.clone()));
+
+            // Unknown commands fail as a missing file does
+            let (code, stdout) = self.outputs.get(&args).cloned().unwrap_or((1, vec![]));
+            Ok(Output {
+                status: exit_status(code),
+                stdout,
+                stderr: vec![],
+            })
+        }
+    }
+
+    #[test]
+    fn decode_utf16_le() {
+        assert_eq!(decode_utf16(&utf16("Ubuntu\r\n")).unwrap(), "Ubuntu\r\n");
+        assert_eq!(decode_utf16(&utf16("日本語")).unwrap(), "日本語");
+        assert_eq!(decode_utf16(&[]).unwrap(), "");
+        // An unpaired surrogate
+        assert!(decode_utf16(&[0x00, 0xd8]).is_err());
+    }
+
+    #[test]
+    fn list_installed_distros() {
+        let (cli, calls) = MockRunner::default()
+            .with(
+                &["--list", "--quiet"],
+                0,
+                utf16("Ubuntu-22.04\r\ndebian-bookworm \r\nMy Distro\r\n"),
+            )
+            .into_cli();
+
+        assert_eq!(
+            cli.list_installed_distros().unwrap(),
+            ["Ubuntu-22.04", "debian-bookworm", "My Distro"]
+        );
+        assert_eq!(calls.borrow()[0].0, "wsl.exe");
+    }
+
+    #[test]
+    fn list_verbose_with_spaces() {
+        let (cli, _) = MockRunner::default()
+            .with(
+                &["--list", "--verbose"],
+                0,
+                utf16(concat!(
+                    "  NAME            STATE           VERSION\r\n",
+                    "* Ubuntu          Running         2\r\n",
+                    "  My Distro       Stopped         2\r\n",
+                    "  legacy          Wird ausgeführt 1\r\n",
+                )),
+            )
+            .into_cli();
+
+        let entries = cli
+            .list_verbose()
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.state, e.version, e.is_default))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                ("Ubuntu".to_string(), "Running".to_string(), Some(2), true),
+                (
+                    "My Distro".to_string(),
+                    "Stopped".to_string(),
+                    Some(2),
+                    false
+                ),
+                (
+                    "legacy".to_string(),
+                    "Wird ausgeführt".to_string(),
+                    Some(1),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn distro_name_with_spaces_is_one_argument() {
+        let (cli, calls) = MockRunner::default()
+            .with(
+                &["-d", "My Distro", "--", "/usr/bin/test", "-e", "/bin/sh"],
+                0,
+                vec![],
+            )
+            .into_cli();
+
+        assert!(cli.file_exists("My Distro", "/bin/sh").unwrap());
+        assert!(!cli.file_exists("My Distro", "/bin/bash").unwrap());
+        assert_eq!(calls.borrow()[0].1[1], "My Distro");
+    }
+
+    #[test]
+    fn sudoers_file_is_not_skipped_by_sudo() {
+        assert_eq!(sudoers_file("alice"), "/etc/sudoers.d/alice");
+        assert_eq!(sudoers_file("john.doe"), "/etc/sudoers.d/john_doe");
+        assert_eq!(sudoers_file("dev-1_x~"), "/etc/sudoers.d/dev-1_x_");
+    }
+
+    #[test]
+    fn find_case_conflict() {
+        let (cli, _) = MockRunner::default()
+            .with(&["--list", "--quiet"], 0, utf16("Ubuntu\r\nMy Distro\r\n"))
+            .into_cli();
+
+        assert_eq!(
+            cli.find_case_conflict("ubuntu").unwrap().as_deref(),
+            Some("Ubuntu")
+        );
+        assert_eq!(
+            cli.find_case_conflict("MY DISTRO").unwrap().as_deref(),
+            Some("My Distro")
+        );
+        // The same name is not a conflict, but the distribution itself
+        assert_eq!(cli.find_case_conflict("Ubuntu").unwrap(), None);
+        assert_eq!(cli.find_case_conflict("debian").unwrap(), None);
+
+        // Nothing is installed
+        let (cli, _) = MockRunner::default().into_cli();
+        assert_eq!(cli.find_case_conflict("ubuntu").unwrap(), None);
+    }
+
+    #[test]
+    fn poll_until_immediate_success() {
+        let mut calls = 0;
+        let start = Instant::now();
+        let ok = poll_until(Duration::from_secs(10), Duration::from_secs(10), || {
+            calls += 1;
+            Ok(true)
+        })
+        .unwrap();
+
+        assert!(ok);
+        assert_eq!(calls, 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn poll_until_success_after_polls() {
+        let mut calls = 0;
+        let ok = poll_until(Duration::from_secs(10), Duration::from_millis(5), || {
+            calls += 1;
+            Ok(calls == 3)
+        })
+        .unwrap();
+
+        assert!(ok);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn poll_until_timeout() {
+        let mut calls = 0;
+        let start = Instant::now();
+        let ok = poll_until(Duration::from_millis(50), Duration::from_millis(10), || {
+            calls += 1;
+            Ok(false)
+        })
+        .unwrap();
+
+        assert!(!ok);
+        assert!((2..=6).contains(&calls), "{} calls", calls);
+        // Doesn't sleep past the timeout
+        assert!(start.elapsed() < Duration::from_millis(50 + 500));
+
+        // The interval is longer than the timeout, so it is checked once
+        let mut calls = 0;
+        let ok = poll_until(Duration::from_millis(10), Duration::from_secs(10), || {
+            calls += 1;
+            Ok(false)
+        })
+        .unwrap();
+        assert!(!ok);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn poll_until_error() {
+        let err = poll_until(Duration::from_secs(10), Duration::from_millis(1), || {
+            bail!("launch failed")
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "launch failed");
+    }
+
+    #[test]
+    fn version() {
+        let (cli, _) = MockRunner::default()
+            .with(
+                &["--version"],
+                0,
+                utf16("WSL version: 2.0.14.0\r\nKernel version: 5.15.133.1-1\r\n"),
+            )
+            .into_cli();
+        assert_eq!(cli.version().unwrap(), "2.0.14.0");
+
+        // The inbox WSL doesn't know --version
+        let (cli, _) = MockRunner::default().into_cli();
+        assert!(cli.version().is_err());
+    }
+
+    #[test]
+    fn parse_uid_output() {
+        let output = |exit_code, stdout: &str, stderr: &str| CapturedOutput {
+            exit_code,
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        };
+
+        assert_eq!(parse_uid(output(0, "1000\n", "")).unwrap(), 1000);
+        assert_eq!(parse_uid(output(0, "0", "")).unwrap(), 0);
+
+        let err = parse_uid(output(1, "", "id: 'alice': no such user\n")).unwrap_err();
+        assert!(err.to_string().contains("no such user"));
+
+        assert!(parse_uid(output(0, "alice\n", "")).is_err());
+    }
+
+    /// Installed distribution to run the `#[ignore]`d tests against a real WSL
+    #[cfg(windows)]
+    fn test_distro() -> String {
+        std::env::var("WSL_GET_TEST_DISTRO")
+            .expect("set WSL_GET_TEST_DISTRO to an installed distribution to test")
+    }
+
+    #[cfg(windows)]
+    #[test]
+    #[ignore]
+    fn set_default_uid_keeps_environment_variables() {
+        let wsl = WSL::new();
+        let distro = test_distro();
+
+        let before = wsl.get_distribution_configuration(&distro).unwrap();
+        defer! {
+            wsl.set_default_uid(&distro, before.default_uid).unwrap();
+        }
+
+        wsl.set_default_uid(&distro, 0).unwrap();
+        let changed = wsl.get_distribution_configuration(&distro).unwrap();
+        assert_eq!(changed.default_uid, 0);
+        assert_eq!(
+            changed.default_environment_variables,
+            before.default_environment_variables
+        );
+
+        wsl.set_default_uid(&distro, before.default_uid).unwrap();
+        let after = wsl.get_distribution_configuration(&distro).unwrap();
+        assert_eq!(after.default_uid, before.default_uid);
+        assert_eq!(
+            after.default_environment_variables,
+            before.default_environment_variables
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    #[ignore]
+    fn set_default_uid_keeps_flags() {
+        let wsl = WSL::new();
+        let distro = test_distro();
+
+        let before = wsl.get_distribution_configuration(&distro).unwrap();
+        defer! {
+            wsl.set_default_uid(&distro, before.default_uid).unwrap();
+        }
+
+        for uid in [0, before.default_uid] {
+            wsl.set_default_uid(&distro, uid).unwrap();
+            let conf = wsl.get_distribution_configuration(&distro).unwrap();
+            assert_eq!(conf.default_uid, uid);
+            assert_eq!(
+                conf.wsl_distribution_flags.0,
+                before.wsl_distribution_flags.0
+            );
+            assert_eq!(conf.distribution_version, before.distribution_version);
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    #[ignore]
+    fn run_capture_separates_outputs() {
+        let output = WSL::new()
+            .run_capture(&test_distro(), "echo out; echo err >&2; exit 3")
+            .unwrap();
+
+        assert_eq!(output.exit_code, 3);
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    #[ignore]
+    fn run_capture_large_outputs() {
+        // Much larger than the pipe buffers. stderr fills up first, which would block
+        // the process if stdout were read to the end before stderr.
+        let output = WSL::new()
+            .run_capture(
+                &test_distro(),
+                "head -c 1048576 /dev/zero >&2; head -c 1048576 /dev/zero",
+            )
+            .unwrap();
+
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(output.stdout.len(), 1 << 20);
+        assert_eq!(output.stderr.len(), 1 << 20);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    #[ignore]
+    fn base_paths_of_installed_distros() {
+        let wsl = WSL::new();
+        let base_paths = wsl.base_paths().unwrap();
+
+        for distro in wsl.list_installed_distros().unwrap() {
+            let path = &base_paths[&distro];
+            assert!(path.is_dir(), "{}: {}", distro, path.display());
+            assert_eq!(wsl.base_path(&distro).unwrap().as_ref(), Some(path));
+        }
+    }
 }
@@ -0,0 +1,62 @@
+//! End-to-end tests against a real WSL. They install and remove distributions,
+//! so they are ignored by default: `cargo test -- --ignored`
+#![cfg(windows)]
+
+use std::process::{Command, Output, Stdio};
+
+fn wsl_get(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_wsl-get"))
+        .args(args)
+        .output()
+        .expect("failed to run wsl-get")
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "stdout: {}\nstderr: {}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+#[ignore]
+fn install_name_with_spaces() {
+    let name = "My Distro";
+
+    assert_success(&wsl_get(&["install", "alpine", name, "--no-user"]));
+
+    let output = wsl_get(&["run", name, "--", "echo", "hello world"]);
+    let uninstalled = wsl_get(&["uninstall", "-y", name]);
+    assert_success(&output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "hello world"
+    );
+    assert_success(&uninstalled);
+
+    let list = wsl_get(&["list"]);
+    assert!(!String::from_utf8_lossy(&list.stdout).contains(name));
+}
+
+#[test]
+#[ignore]
+fn install_no_user_without_terminal() {
+    let dir = tempfile::tempdir().unwrap();
+    let tarball = dir.path().join("alpine.tar.gz");
+    let tarball = tarball.to_str().unwrap();
+    assert_success(&wsl_get(&["download", "alpine", "--output", tarball]));
+
+    let name = "wsl-get-test-no-user";
+    let output = Command::new(env!("CARGO_BIN_EXE_wsl-get"))
+        .args(["install", "--no-user", "--from-file", tarball, name])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let uninstalled = wsl_get(&["uninstall", "-y", name]);
+
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("not a terminal"));
+    assert_success(&output);
+    assert_success(&uninstalled);
+}